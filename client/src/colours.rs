@@ -9,5 +9,6 @@ pub fn point_colour(point_type: &PointType) -> Color32 {
         PointType::Obstacle => Color32::DARK_RED,
         PointType::ArrowLeft => Color32::WHITE,
         PointType::ArrowRight => Color32::WHITE,
+        PointType::FinishLine => Color32::LIGHT_GREEN,
     }
 }
@@ -34,12 +34,48 @@ pub fn picture_taker(ui: &mut egui::Ui, command: &mut messages::command::DriveCo
         if ui.button("Yel Mask").clicked() {
             command.images_yellow += 1;
         }
+        if ui.button("Overlay").clicked() {
+            command.images_overlay += 1;
+        }
+        ui.checkbox(&mut command.record, "Record run");
+        if ui.button("Black box").clicked() {
+            command.black_box_dumps += 1;
+        }
         if ui.button("Sync").clicked() {
             do_rsync(ip);
         }
     });
 }
 
+// Only does anything when the planner is running from a recording
+pub fn playback_controls(
+    ui: &mut egui::Ui, command: &mut messages::command::DriveCommand, diagnostic: &messages::diagnostic::Diagnostic,
+) {
+    let playback = command.playback.get_or_insert_with(|| messages::command::PlaybackCommand {
+        speed: 1.0,
+        ..Default::default()
+    });
+    ui.horizontal(|ui| {
+        // the planner can pause itself at the end of a recording, so go by what it says
+        let pause_text = if diagnostic.playback_paused { "Play" } else { "Pause" };
+        if ui.button(pause_text).clicked() {
+            playback.pause_toggles += 1;
+        }
+        if ui.button("<").clicked() {
+            playback.steps_back += 1;
+        }
+        if ui.button(">").clicked() {
+            playback.steps_forward += 1;
+        }
+        ui.add(egui::Slider::new(&mut playback.speed, 0.05..=8.0).logarithmic(true).text("x"));
+        ui.add(egui::DragValue::new(&mut playback.seek_frame).prefix("frame "));
+        if ui.button("Seek").clicked() {
+            playback.seeks += 1;
+        }
+        ui.label(format!("At {}", diagnostic.frame_index));
+    });
+}
+
 fn do_rsync(ip: &str) {
     let from_dir = if ip == "127.0.0.1" { 
         "~/car/drc/planner/images/*.png"
@@ -96,15 +132,20 @@ pub fn map_display(ui: &mut egui::Ui, map: &Vec<MapPointWithTime>, path: &messag
             y: point.inner.y - map_center.y,
         };
         let point_type = PointType::try_from(point.inner.point_type).unwrap();
+        // faded out the less sure vision was about it
+        let colour = point_colour(&point_type).gamma_multiply(point.inner.confidence.clamp(0.2, 1.0));
         let c = match point_type {
             PointType::ArrowLeft => "⟲",
             PointType::ArrowRight => "⟳",
             _ => "",
         };
-        if c.len() == 0 {
-            paint.circle(in_rect(pos * map_scale, MAP_RECT), 1., point_colour(&point_type), Stroke::NONE);
+        if c.len() == 0 && point.inner.is_virtual {
+            // hollow so made up lines can be told apart from seen ones
+            paint.circle_stroke(in_rect(pos * map_scale, MAP_RECT), 1.5, Stroke::new(0.5, colour));
+        } else if c.len() == 0 {
+            paint.circle(in_rect(pos * map_scale, MAP_RECT), 1., colour, Stroke::NONE);
         } else {
-            paint.text(in_rect(pos * map_scale, MAP_RECT), Align2::CENTER_CENTER, c, egui::FontId::monospace(12.0), point_colour(&point_type));
+            paint.text(in_rect(pos * map_scale, MAP_RECT), Align2::CENTER_CENTER, c, egui::FontId::monospace(12.0), colour);
         }
     }
 
@@ -13,7 +13,9 @@ mod messages {
     }
 }
 use comms::{start_request_loop, CommsState, CONNECTED_TIMEOUT};
-use components::{change_command_from_keys, driver_display, map_display, picture_taker, state_selector};
+use components::{
+    change_command_from_keys, driver_display, map_display, picture_taker, playback_controls, state_selector,
+};
 use eframe::egui::{self, Color32, Pos2, RichText};
 use messages::command::CommandMode;
 use std::{
@@ -97,6 +99,17 @@ fn main() -> Result<(), eframe::Error> {
                     let fps_low = diag.framerate_90;
                     ui.label(format!("Car fps avg: {:.2}", fps_avg));
                     ui.label(format!("Car fps low: {:.2}", fps_low));
+                    ui.label(format!("Dropped frames: {}", diag.dropped_frames));
+                    match diag.localization {
+                        Some(c) => ui.label(format!("Localization: {:.3}m {:.3}m {:.3}rad", c.x, c.y, c.angle)),
+                        None => ui.label("Localization: -"),
+                    };
+                    if diag.recording {
+                        ui.label(RichText::new("Recording").color(Color32::RED));
+                    }
+                    if diag.finish_line_ahead {
+                        ui.label(RichText::new("Finish line ahead").color(Color32::LIGHT_GREEN));
+                    }
                 } else {
                     ui.label(format!("No diagnostic recieved"));
                     ui.label(format!("-"));
@@ -108,6 +121,8 @@ fn main() -> Result<(), eframe::Error> {
                 points_per_second = points_per_second * (1.0-alpha) + points_added * alpha;
                 ui.label(format!("Points: {} ({:.1}/t)", state.map.len(), points_per_second));
                 picture_taker(ui, &mut state.command_to_send, &ip_str);
+                let diagnostic = state.last_recieved_diagnostic.diagnostic.clone().unwrap_or_default();
+                playback_controls(ui, &mut state.command_to_send, &diagnostic);
             }
         });
         ctx.request_repaint();
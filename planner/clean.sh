@@ -1,3 +1,6 @@
 rm session_*.log
 rm heaptrack*.zst
 rm output*.avi
+rm run_*.avi
+rm run_*.telemetry
+rm -r blackbox_*
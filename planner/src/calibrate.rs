@@ -0,0 +1,445 @@
+// Tools to measure the camera and write the results to config.json
+//
+// `planner calibrate-lens [image dir]` estimates the lens intrinsics and distortion from the checkerboard
+// images saved by Recorder, the board can be held at any angle but should cover the edges of the frame
+//
+// `planner calibrate [frame source]` interactively measures the perspective transform, in the window:
+//   space - freeze/unfreeze the frame
+//   c     - find the checkerboard, whose inner corners should span the calibration target
+//   t     - find a square of blue tape, whose outside edge is the calibration target
+//   click - the 4 corners of the calibration target, in any order
+//   r     - clear clicked points
+//   w     - write the last fit to config.json
+//   q/esc - quit
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+};
+
+use opencv::{
+    calib3d::{
+        calibrate_camera_def, draw_chessboard_corners, find_chessboard_corners, find_homography,
+        CALIB_CB_ADAPTIVE_THRESH, CALIB_CB_NORMALIZE_IMAGE,
+    },
+    core::{
+        in_range, invert, perspective_transform, Mat, MatTraitConst, MatTraitConstManual, Point2f, Point3f, Size,
+        TermCriteria, TermCriteria_Type, VecN, Vector, DECOMP_LU,
+    },
+    highgui,
+    imgcodecs::{imread, IMREAD_GRAYSCALE},
+    imgproc::{
+        approx_poly_dp, arc_length, circle, contour_area_def, corner_sub_pix, cvt_color, find_contours, polylines,
+        put_text, ColorConversionCodes, ContourApproximationModes, HersheyFonts, LineTypes, RetrievalModes,
+    },
+    types::VectorOfVectorOfPoint,
+    Result,
+};
+use serde::Serialize;
+
+use crate::{
+    camera::{is_recorded_frame, FrameSource},
+    config::file::{CalibrationConfig, Config, ConfigReader, LensConfig, PerspectiveConfig},
+};
+
+const WINDOW: &str = "calibrate";
+
+// Image and ground positions of matching points
+struct Correspondences {
+    image: Vector<Point2f>,
+    ground: Vector<Point2f>,
+}
+
+struct Fit {
+    perspective: PerspectiveConfig,
+    // image positions of the target corners, for drawing
+    corners: Vec<Point2f>,
+    rms_error: f64,
+}
+
+pub fn run_calibration(
+    camera: &mut dyn FrameSource, config: &mut ConfigReader<Config>, config_path: &str,
+) -> Result<()> {
+    highgui::named_window(WINDOW, highgui::WINDOW_AUTOSIZE)?;
+    let clicked = Arc::new(Mutex::new(Vec::<Point2f>::new()));
+    let clicked_callback = Arc::clone(&clicked);
+    highgui::set_mouse_callback(
+        WINDOW,
+        Some(Box::new(move |event, x, y, _flags| {
+            if event == highgui::EVENT_LBUTTONDOWN {
+                let mut points = clicked_callback.lock().unwrap();
+                if points.len() < 4 {
+                    points.push(Point2f { x: x as f32, y: y as f32 });
+                }
+            }
+        })),
+    )?;
+
+    let mut frame = Mat::default();
+    let mut frozen = false;
+    let mut detected = Vector::<Point2f>::new();
+    let mut fit: Option<Fit> = None;
+    let mut status = "space to freeze, c checkerboard, t tape, click corners".to_owned();
+
+    loop {
+        if !frozen {
+            frame = match camera.next_frame() {
+                Some(next) => next.image,
+                None if camera.has_finished() => return Ok(()),
+                // the camera hasn't captured a new frame yet
+                None => {
+                    highgui::wait_key(1)?;
+                    continue;
+                }
+            };
+        }
+
+        let calibration_cfg = &config.get_value().calibration_cfg;
+
+        let clicked_points = clicked.lock().unwrap().clone();
+        if clicked_points.len() == 4 && fit.is_none() {
+            let image = Vector::from_iter(sort_corners(&clicked_points));
+            let correspondences = Correspondences {
+                image,
+                ground: Vector::from_iter(target_corners(calibration_cfg)),
+            };
+            fit = fit_perspective(&correspondences, calibration_cfg).ok();
+            status = describe_fit(&fit);
+        }
+
+        let mut display = frame.clone();
+        draw_overlay(&mut display, &clicked_points, &detected, calibration_cfg, &fit, &status)?;
+        highgui::imshow(WINDOW, &display)?;
+
+        let key = highgui::wait_key(20)?;
+        match char::from_u32(key as u32).unwrap_or_default() {
+            ' ' => frozen = !frozen,
+            'c' => {
+                let found = find_checkerboard(&frame, calibration_cfg)?;
+                (detected, fit) = match found {
+                    Some(correspondences) => {
+                        let fit = fit_perspective(&correspondences, calibration_cfg).ok();
+                        (correspondences.image, fit)
+                    }
+                    None => (Vector::new(), None),
+                };
+                status = describe_fit(&fit);
+            }
+            't' => {
+                let blue = config.get_value().blue_colour.to_opencv_range();
+                let calibration_cfg = &config.get_value().calibration_cfg;
+                let found = find_tape_square(&frame, &blue.low, &blue.high)?;
+                (detected, fit) = match found {
+                    Some(corners) => {
+                        let correspondences = Correspondences {
+                            image: Vector::from_iter(corners),
+                            ground: Vector::from_iter(target_corners(calibration_cfg)),
+                        };
+                        let fit = fit_perspective(&correspondences, calibration_cfg).ok();
+                        (correspondences.image, fit)
+                    }
+                    None => (Vector::new(), None),
+                };
+                status = describe_fit(&fit);
+            }
+            'r' => {
+                clicked.lock().unwrap().clear();
+                detected.clear();
+                fit = None;
+                status = "cleared".to_owned();
+            }
+            'w' => {
+                if let Some(current_fit) = &fit {
+                    let perspective = &current_fit.perspective;
+                    let written = write_config(config_path, |config| {
+                        config.perspective = PerspectiveConfig {
+                            image: perspective.image.clone(),
+                            ground: perspective.ground.clone(),
+                        }
+                    });
+                    status = match written {
+                        Ok(()) => format!("wrote perspective to {}", config_path),
+                        Err(e) => format!("could not write config: {}", e),
+                    };
+                    println!("{}", status);
+                }
+            }
+            'q' | '\u{1b}' => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+// Orders the points as near left, near right, far right, far left, which is the order used in config.json
+fn sort_corners(points: &[Point2f]) -> Vec<Point2f> {
+    let mut by_height = points.to_vec();
+    // image y increases downwards so the nearest points have the largest y
+    by_height.sort_by(|a, b| b.y.total_cmp(&a.y));
+    let (near, far) = by_height.split_at_mut(2);
+    near.sort_by(|a, b| a.x.total_cmp(&b.x));
+    far.sort_by(|a, b| b.x.total_cmp(&a.x));
+    by_height
+}
+
+// Ground positions of the target in the same order as sort_corners
+fn target_corners(cfg: &CalibrationConfig) -> Vec<Point2f> {
+    let x = cfg.target_near_left[0];
+    let y = cfg.target_near_left[1];
+    vec![
+        Point2f { x, y },
+        Point2f { x: x + cfg.target_width, y },
+        Point2f { x: x + cfg.target_width, y: y + cfg.target_length },
+        Point2f { x, y: y + cfg.target_length },
+    ]
+}
+
+fn find_checkerboard(frame: &Mat, cfg: &CalibrationConfig) -> Result<Option<Correspondences>> {
+    let cols = cfg.board_inner_corners[0];
+    let rows = cfg.board_inner_corners[1];
+    let mut corners = Vector::<Point2f>::new();
+    let found = find_chessboard_corners(
+        frame,
+        Size::new(cols, rows),
+        &mut corners,
+        CALIB_CB_ADAPTIVE_THRESH + CALIB_CB_NORMALIZE_IMAGE,
+    )?;
+    if !found || corners.len() != (cols * rows) as usize {
+        return Ok(None);
+    }
+
+    // the board can be detected starting from any corner, so work out which grid corner
+    // is which target corner and interpolate the ground positions of the rest between them
+    let grid_extremes = [(0, 0), (cols - 1, 0), (cols - 1, rows - 1), (0, rows - 1)];
+    let extreme_images: Vec<Point2f> = grid_extremes
+        .iter()
+        .map(|(i, j)| corners.get((j * cols + i) as usize))
+        .collect::<Result<_>>()?;
+    let sorted = sort_corners(&extreme_images);
+    let target = target_corners(cfg);
+    let ground_of_extreme: Vec<Point2f> = extreme_images
+        .iter()
+        .map(|p| target[sorted.iter().position(|s| s == p).unwrap_or_default()])
+        .collect();
+
+    let mut ground = Vector::<Point2f>::new();
+    for j in 0..rows {
+        for i in 0..cols {
+            let u = i as f32 / (cols - 1) as f32;
+            let v = j as f32 / (rows - 1) as f32;
+            let weights = [(1. - u) * (1. - v), u * (1. - v), u * v, (1. - u) * v];
+            ground.push(Point2f {
+                x: (0..4).map(|k| ground_of_extreme[k].x * weights[k]).sum(),
+                y: (0..4).map(|k| ground_of_extreme[k].y * weights[k]).sum(),
+            });
+        }
+    }
+
+    Ok(Some(Correspondences { image: corners, ground }))
+}
+
+// Finds the outside corners of the largest blob of tape
+fn find_tape_square(frame: &Mat, low: &VecN<u8, 3>, high: &VecN<u8, 3>) -> Result<Option<Vec<Point2f>>> {
+    let mut hsv = Mat::default();
+    cvt_color(frame, &mut hsv, ColorConversionCodes::COLOR_BGR2HSV.into(), 0)?;
+    let mut mask = Mat::default();
+    in_range(&hsv, low, high, &mut mask)?;
+    let mut contours = VectorOfVectorOfPoint::new();
+    find_contours(
+        &mask,
+        &mut contours,
+        RetrievalModes::RETR_EXTERNAL.into(),
+        ContourApproximationModes::CHAIN_APPROX_SIMPLE.into(),
+        opencv::core::Point { x: 0, y: 0 },
+    )?;
+
+    let largest = contours
+        .iter()
+        .max_by(|a, b| contour_area_def(a).unwrap_or_default().total_cmp(&contour_area_def(b).unwrap_or_default()));
+    let largest = match largest {
+        Some(contour) => contour,
+        None => return Ok(None),
+    };
+
+    let mut approx = Vector::<opencv::core::Point>::new();
+    approx_poly_dp(&largest, &mut approx, arc_length(&largest, true)? * 0.02, true)?;
+    if approx.len() != 4 {
+        return Ok(None);
+    }
+    let corners: Vec<Point2f> = approx.iter().map(|p| Point2f { x: p.x as f32, y: p.y as f32 }).collect();
+    Ok(Some(sort_corners(&corners)))
+}
+
+fn fit_perspective(correspondences: &Correspondences, cfg: &CalibrationConfig) -> Result<Fit> {
+    let mut mask = Mat::default();
+    // least squares over all points
+    let transform = find_homography(&correspondences.image, &correspondences.ground, &mut mask, 0, 3.0)?;
+
+    let mut projected = Vector::<Point2f>::new();
+    perspective_transform(&correspondences.image, &mut projected, &transform)?;
+    let squared_error: f64 = projected
+        .iter()
+        .zip(correspondences.ground.iter())
+        .map(|(a, b)| ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)) as f64)
+        .sum();
+    let rms_error = (squared_error / projected.len() as f64).sqrt();
+
+    // config.json only stores 4 pairs, so store where the fitted transform puts the target corners
+    let mut inverse = Mat::default();
+    invert(&transform, &mut inverse, DECOMP_LU)?;
+    let ground = Vector::from_iter(target_corners(cfg));
+    let mut image = Vector::<Point2f>::new();
+    perspective_transform(&ground, &mut image, &inverse)?;
+
+    Ok(Fit {
+        perspective: PerspectiveConfig {
+            image: image.iter().map(|p| vec![p.x.round(), p.y.round()]).collect(),
+            ground: ground.iter().map(|p| vec![p.x, p.y]).collect(),
+        },
+        corners: image.to_vec(),
+        rms_error,
+    })
+}
+
+fn describe_fit(fit: &Option<Fit>) -> String {
+    let description = match fit {
+        Some(fit) => format!("reprojection error {:.1}mm rms, w to write", fit.rms_error * 1000.),
+        None => "no fit found".to_owned(),
+    };
+    println!("{}", description);
+    description
+}
+
+fn draw_overlay(
+    display: &mut Mat, clicked: &[Point2f], detected: &Vector<Point2f>, cfg: &CalibrationConfig, fit: &Option<Fit>,
+    status: &str,
+) -> Result<()> {
+    let red = VecN::<f64, 4> { 0: [0., 0., 255., 0.] };
+    let green = VecN::<f64, 4> { 0: [0., 255., 0., 0.] };
+    let white = VecN::<f64, 4> { 0: [255., 255., 255., 0.] };
+
+    if !detected.is_empty() {
+        let pattern = Size::new(cfg.board_inner_corners[0], cfg.board_inner_corners[1]);
+        if detected.len() == (pattern.width * pattern.height) as usize {
+            draw_chessboard_corners(display, pattern, detected, true)?;
+        } else {
+            for p in detected.iter() {
+                circle(display, to_int(p), 4, red, -1, LineTypes::LINE_8.into(), 0)?;
+            }
+        }
+    }
+    for p in clicked {
+        circle(display, to_int(*p), 4, red, -1, LineTypes::LINE_8.into(), 0)?;
+    }
+    if let Some(fit) = fit {
+        let outline = Vector::<opencv::core::Point>::from_iter(fit.corners.iter().map(|p| to_int(*p)));
+        polylines(display, &outline, true, green, 2, LineTypes::LINE_8.into(), 0)?;
+    }
+    let size = display.size()?;
+    put_text(
+        display,
+        status,
+        opencv::core::Point { x: 10, y: size.height - 10 },
+        HersheyFonts::FONT_HERSHEY_SIMPLEX.into(),
+        0.5,
+        white,
+        1,
+        LineTypes::LINE_8.into(),
+        false,
+    )?;
+    Ok(())
+}
+
+fn to_int(p: Point2f) -> opencv::core::Point {
+    opencv::core::Point {
+        x: p.x.round() as i32,
+        y: p.y.round() as i32,
+    }
+}
+
+pub fn run_lens_calibration(image_dir: &str, config: &mut ConfigReader<Config>, config_path: &str) -> Result<()> {
+    let board = &config.get_value().calibration_cfg.board_inner_corners;
+    let pattern = Size::new(board[0], board[1]);
+    // the square size only scales the board poses, which aren't used
+    let board_points = Vector::<Point3f>::from_iter(
+        (0..pattern.height).flat_map(|j| (0..pattern.width).map(move |i| Point3f { x: i as f32, y: j as f32, z: 0. })),
+    );
+
+    let mut object_points = Vector::<Vector<Point3f>>::new();
+    let mut image_points = Vector::<Vector<Point2f>>::new();
+    let mut image_size = Size::default();
+    let criteria = TermCriteria::new(TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32, 30, 0.001)?;
+
+    let mut paths: Vec<_> = fs::read_dir(image_dir)
+        .map_err(|e| opencv::Error::new(0, e.to_string()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| is_recorded_frame(path))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let gray = imread(&path.to_string_lossy(), IMREAD_GRAYSCALE)?;
+        let mut corners = Vector::<Point2f>::new();
+        let flags = CALIB_CB_ADAPTIVE_THRESH + CALIB_CB_NORMALIZE_IMAGE;
+        let found = find_chessboard_corners(&gray, pattern, &mut corners, flags)?;
+        println!("{}: {}", path.display(), if found { "found board" } else { "no board" });
+        if !found {
+            continue;
+        }
+        corner_sub_pix(&gray, &mut corners, Size::new(11, 11), Size::new(-1, -1), criteria)?;
+        object_points.push(board_points.clone());
+        image_points.push(corners);
+        image_size = gray.size()?;
+    }
+
+    if image_points.len() < 3 {
+        println!("Need at least 3 images with the board visible, found {}", image_points.len());
+        return Ok(());
+    }
+
+    let mut camera_matrix = Mat::default();
+    let mut distortion = Mat::default();
+    let mut rvecs = Vector::<Mat>::new();
+    let mut tvecs = Vector::<Mat>::new();
+    let rms = calibrate_camera_def(
+        &object_points,
+        &image_points,
+        image_size,
+        &mut camera_matrix,
+        &mut distortion,
+        &mut rvecs,
+        &mut tvecs,
+    )?;
+    println!("Calibrated from {} images, reprojection error {:.2}px rms", image_points.len(), rms);
+
+    let mut matrix_rows = Vec::new();
+    for row in 0..3 {
+        let mut values = Vec::new();
+        for col in 0..3 {
+            values.push(*camera_matrix.at_2d::<f64>(row, col)?);
+        }
+        matrix_rows.push(values);
+    }
+    let lens = LensConfig {
+        camera_matrix: matrix_rows,
+        distortion: distortion.data_typed::<f64>()?.to_vec(),
+    };
+    println!("{:?}", lens);
+
+    match write_config(config_path, |config| config.lens_cfg = lens) {
+        Ok(()) => println!("wrote lens_cfg to {}", config_path),
+        Err(e) => println!("could not write config: {}", e),
+    }
+    Ok(())
+}
+
+// Changes part of the config file, ConfigReader will notice the file changed and reload it
+fn write_config(config_path: &str, update: impl FnOnce(&mut Config)) -> std::result::Result<(), String> {
+    let contents = fs::read_to_string(config_path).map_err(|e| e.to_string())?;
+    let mut config = serde_json::from_str::<Config>(&contents).map_err(|e| e.to_string())?;
+    update(&mut config);
+
+    let mut output = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut serializer = serde_json::Serializer::with_formatter(&mut output, formatter);
+    config.serialize(&mut serializer).map_err(|e| e.to_string())?;
+    fs::write(config_path, output).map_err(|e| e.to_string())
+}
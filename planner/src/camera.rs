@@ -1,16 +1,96 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use opencv::{
-    core::{Mat, MatTraitConst, Vector}, highgui, imgcodecs::imwrite, videoio::{self, VideoCaptureTrait, VideoCaptureTraitConst, CAP_PROP_POS_FRAMES}
+    core::{mean, no_array, Mat, MatTraitConst, Rect, VecN, Vector, CV_8UC3},
+    highgui,
+    imgcodecs::{imread, imwrite, IMREAD_COLOR},
+    imgproc::{fill_convex_poly, LineTypes},
+    videoio::{self, VideoCaptureTrait, VideoCaptureTraitConst, CAP_PROP_POS_FRAMES, CAP_PROP_POS_MSEC},
 };
+use prost::Message;
 use time::OffsetDateTime;
 
-use crate::{config::display::SHOULD_DISPLAY_RAW_VIDEO, display::annotate_video, messages};
+use crate::{
+    config::{display::SHOULD_DISPLAY_RAW_VIDEO, file::{CameraConfig, Config}},
+    display::annotate_video,
+    messages::{self, diagnostic::TelemetryFrame},
+};
+
+// A frame from a FrameSource
+pub struct Frame {
+    pub image: Mat,
+    // seconds, since the unix epoch for live sources or since the start for recorded ones
+    pub timestamp: f64,
+    // position of the frame in the source, counting from 0
+    pub index: u64,
+}
+
+// Somewhere frames come from, so the rest of the planner doesn't care if it is running live or from a recording
+pub trait FrameSource {
+    // None when there are no frames left, or for sources that don't wait for frames when there isn't a new one yet
+    fn next_frame(&mut self) -> Option<Frame>;
+
+    // If next_frame returning None means there won't be any more frames
+    fn has_finished(&self) -> bool {
+        true
+    }
+
+    // Total frames that were captured but replaced by a newer one before they were used
+    fn dropped_frames(&self) -> u64 {
+        0
+    }
+
+    // Only does anything for sources that are a real camera
+    fn apply_camera_settings(&mut self, _settings: &CameraConfig) {}
+
+    // If seek can be used, which is only for recordings
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    // Makes the next frame the one with this index
+    fn seek(&mut self, _index: u64) {}
+}
+
+// Opens the source given on the command line
+// nothing for the camera, "pattern" for a generated test pattern, a directory of pngs or a video file
+pub fn open_frame_source(arg: Option<&String>) -> Box<dyn FrameSource> {
+    match arg {
+        None => Box::new(ThreadedCapture::new(Box::new(Capture::camera()))),
+        Some(arg) if arg == "pattern" => Box::new(TestPattern::new()),
+        Some(arg) if Path::new(arg).is_dir() => Box::new(ImageDirectory::new(arg)),
+        Some(arg) => Box::new(Capture::video(arg)),
+    }
+}
+
+fn unix_time() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64()
+}
+
+// Values of CAP_PROP_AUTO_EXPOSURE for the v4l2 backend, which passes them straight to the driver
+const V4L2_EXPOSURE_MANUAL: f64 = 1.0;
+const V4L2_EXPOSURE_APERTURE_PRIORITY: f64 = 3.0;
+// Frames between software exposure updates, the camera takes a few frames to apply a new exposure
+const SOFTWARE_EXPOSURE_INTERVAL: u64 = 5;
 
 pub struct Capture {
     inner: videoio::VideoCapture,
-    frame: Mat,
-    needs_restarting: bool,
+    is_file: bool,
+    // count of frames read, for the camera which doesn't know its position
+    frames_read: u64,
+    settings: Option<CameraConfig>,
+    // the exposure currently set on the camera, changed by software exposure
+    exposure: f64,
 }
 
 impl Capture {
@@ -33,8 +113,10 @@ impl Capture {
 
         Capture {
             inner: cap,
-            frame: Mat::default(),
-            needs_restarting: false,
+            is_file: false,
+            frames_read: 0,
+            settings: None,
+            exposure: 0.,
         }
     }
 
@@ -50,46 +132,410 @@ impl Capture {
 
         Capture {
             inner: cap,
-            frame: Mat::default(),
-            needs_restarting: true,
+            is_file: true,
+            frames_read: 0,
+            settings: None,
+            exposure: 0.,
         }
     }
 
-    pub fn get_frame(&mut self) -> Option<&Mat> {
+    fn set_property(&mut self, property: i32, value: f64) {
+        if !self.inner.set(property, value).unwrap_or_default() {
+            println!("camera didn't accept setting {} to {}", property, value);
+        }
+    }
+
+    // Nudges the exposure so the average brightness of the region moves towards the target
+    fn update_software_exposure(&mut self, image: &Mat) {
+        let settings = match &self.settings {
+            Some(settings) if !settings.auto_exposure && settings.software_exposure.enabled => settings,
+            _ => return,
+        };
+        if self.frames_read % SOFTWARE_EXPOSURE_INTERVAL != 0 {
+            return;
+        }
         puffin::profile_function!();
-        let got_frame = self.inner.read(&mut self.frame).unwrap_or_default();
 
-        if !got_frame && self.needs_restarting {
-            self.inner.set(CAP_PROP_POS_FRAMES, 0.0).ok()?;
-            println!("restarting video");
-            return self.get_frame();
+        let software = &settings.software_exposure;
+        let brightness = match region_brightness(image, &software.region) {
+            Some(brightness) => brightness,
+            None => return,
+        };
+        // exposure is roughly proportional to brightness, so scale it by how far off the brightness is
+        let scale = (software.target_brightness / brightness.max(1.)).powf(software.rate);
+        let exposure = (self.exposure * scale).clamp(software.min_exposure, software.max_exposure);
+        if (exposure - self.exposure).abs() > self.exposure * 0.01 {
+            self.exposure = exposure;
+            self.set_property(videoio::CAP_PROP_EXPOSURE, exposure);
         }
+    }
+}
+
+// Average brightness of part of a bgr image, None if the region is outside the image
+fn region_brightness(image: &Mat, region: &[i32; 4]) -> Option<f64> {
+    let size = image.size().ok()?;
+    let [x, y, width, height] = *region;
+    let x = x.clamp(0, size.width);
+    let y = y.clamp(0, size.height);
+    let roi = Rect {
+        x,
+        y,
+        width: width.min(size.width - x),
+        height: height.min(size.height - y),
+    };
+    if roi.width <= 0 || roi.height <= 0 {
+        return None;
+    }
+    let bgr = mean(&image.apply_1(roi).ok()?, &no_array()).ok()?;
+    Some(0.114 * bgr[0] + 0.587 * bgr[1] + 0.299 * bgr[2])
+}
+
+impl FrameSource for Capture {
+    fn next_frame(&mut self) -> Option<Frame> {
+        puffin::profile_function!();
 
-        if display_image_and_get_key(&self.frame) {
+        let mut image = Mat::default();
+        if !self.inner.read(&mut image).unwrap_or_default() {
             return None;
         }
+        self.frames_read += 1;
+        self.update_software_exposure(&image);
+
+        // video files know where they are up to, the camera doesn't
+        Some(if self.is_file {
+            // position after reading, so the frame just read is one before
+            let position = self.inner.get(CAP_PROP_POS_FRAMES).unwrap_or_default();
+            Frame {
+                image,
+                timestamp: self.inner.get(CAP_PROP_POS_MSEC).unwrap_or_default() / 1000.,
+                index: (position as u64).saturating_sub(1),
+            }
+        } else {
+            Frame {
+                image,
+                timestamp: unix_time(),
+                index: self.frames_read - 1,
+            }
+        })
+    }
+
+    fn can_seek(&self) -> bool {
+        self.is_file
+    }
+
+    fn seek(&mut self, index: u64) {
+        if self.is_file && !self.inner.set(CAP_PROP_POS_FRAMES, index as f64).unwrap_or_default() {
+            println!("could not seek to frame {}", index);
+        }
+    }
+
+    fn apply_camera_settings(&mut self, settings: &CameraConfig) {
+        // video files don't have settings
+        if self.is_file {
+            return;
+        }
+        println!("applying camera settings");
+
+        if settings.auto_exposure {
+            self.set_property(videoio::CAP_PROP_AUTO_EXPOSURE, V4L2_EXPOSURE_APERTURE_PRIORITY);
+        } else {
+            self.set_property(videoio::CAP_PROP_AUTO_EXPOSURE, V4L2_EXPOSURE_MANUAL);
+            self.set_property(videoio::CAP_PROP_EXPOSURE, settings.exposure);
+            self.set_property(videoio::CAP_PROP_GAIN, settings.gain);
+        }
+        self.set_property(videoio::CAP_PROP_AUTO_WB, if settings.auto_white_balance { 1. } else { 0. });
+        if !settings.auto_white_balance {
+            self.set_property(videoio::CAP_PROP_WB_TEMPERATURE, settings.white_balance);
+        }
+        self.exposure = settings.exposure;
+        self.settings = Some(settings.clone());
+    }
+}
+
+// The newest frame from the capture thread, waiting to be used
+#[derive(Default)]
+struct LatestFrame {
+    frame: Option<Frame>,
+    dropped: u64,
+    // the source ran out of frames
+    finished: bool,
+}
+
+// Reads frames on its own thread so waiting for the camera overlaps with vision and planning
+// Only the newest frame is kept, older ones that weren't used in time are dropped
+pub struct ThreadedCapture {
+    latest: Arc<Mutex<LatestFrame>>,
+    // settings waiting to be applied by the capture thread, which owns the camera
+    settings: Arc<Mutex<Option<CameraConfig>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    dropped: u64,
+}
+
+impl ThreadedCapture {
+    pub fn new(mut source: Box<dyn FrameSource + Send>) -> ThreadedCapture {
+        let latest = Arc::new(Mutex::new(LatestFrame::default()));
+        let settings = Arc::new(Mutex::new(None::<CameraConfig>));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_latest = Arc::clone(&latest);
+        let thread_settings = Arc::clone(&settings);
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::Builder::new()
+            .name("capture".to_owned())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Relaxed) {
+                    if let Some(settings) = thread_settings.lock().unwrap().take() {
+                        source.apply_camera_settings(&settings);
+                    }
+                    let frame = source.next_frame();
+                    let mut latest = thread_latest.lock().unwrap();
+                    match frame {
+                        Some(frame) => {
+                            if latest.frame.replace(frame).is_some() {
+                                latest.dropped += 1;
+                            }
+                        }
+                        None => {
+                            latest.finished = true;
+                            return;
+                        }
+                    }
+                }
+            })
+            .unwrap();
+
+        ThreadedCapture {
+            latest,
+            settings,
+            stop,
+            thread: Some(thread),
+            dropped: 0,
+        }
+    }
+}
+
+impl FrameSource for ThreadedCapture {
+    // Takes the newest frame, None if it has already been used so the main loop doesn't wait for the camera
+    fn next_frame(&mut self) -> Option<Frame> {
+        puffin::profile_function!();
+
+        let mut latest = self.latest.lock().unwrap();
+        self.dropped = latest.dropped;
+        latest.frame.take()
+    }
+
+    fn has_finished(&self) -> bool {
+        let latest = self.latest.lock().unwrap();
+        latest.finished && latest.frame.is_none()
+    }
+
+    fn dropped_frames(&self) -> u64 {
+        self.dropped
+    }
 
-        return if got_frame { Some(&self.frame) } else { None };
+    fn apply_camera_settings(&mut self, settings: &CameraConfig) {
+        *self.settings.lock().unwrap() = Some(settings.clone());
     }
 }
 
-pub fn display_image_and_get_key(_frame: &Mat) -> bool {
+impl Drop for ThreadedCapture {
+    // Waits for the capture thread to finish with the camera, so it can be opened again straight away
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Name the Recorder saves whole camera frames under
+pub const FRAME_IMAGE: &str = "image";
+
+// Whole camera frames saved by the Recorder as image-<time>.png or by the black box as frame-<n>.png
+// The masks and overlays saved next to them aren't frames
+pub fn is_recorded_frame(path: &Path) -> bool {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    (name.starts_with(&format!("{FRAME_IMAGE}-")) || name.starts_with("frame-")) && name.ends_with(".png")
+}
+
+// Plays back full size frames saved as pngs in a directory, in filename order
+pub struct ImageDirectory {
+    files: Vec<PathBuf>,
+    next: usize,
+    // capture times from the telemetry the black box saves next to its frames, by frame number
+    telemetry_timestamps: HashMap<u64, f64>,
+}
+
+impl ImageDirectory {
+    pub fn new(dir: &str) -> ImageDirectory {
+        println!("Opening image directory {}", dir);
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| is_recorded_frame(path))
+            .collect();
+        files.sort();
+        if files.is_empty() {
+            panic!("No recorded frames in {}", dir);
+        }
+
+        ImageDirectory {
+            files,
+            next: 0,
+            telemetry_timestamps: read_telemetry_timestamps(&Path::new(dir).join("telemetry")),
+        }
+    }
+
+    fn timestamp(&self, path: &Path) -> f64 {
+        // the black box saves all its frames at once, so they are timed from the telemetry or the frame number
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let black_box_index = name.strip_prefix("frame-").and_then(|n| n.strip_suffix(".png")?.parse::<u64>().ok());
+        if let Some(index) = black_box_index {
+            return match self.telemetry_timestamps.get(&index) {
+                Some(timestamp) => *timestamp,
+                None => index as f64 / BLACK_BOX_FPS,
+            };
+        }
+        // the Recorder saves each frame as it is captured
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map_or(0., |time| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64())
+    }
+}
+
+// Frame rate assumed for black box frames saved without their telemetry
+const BLACK_BOX_FPS: f64 = 30.;
+
+// Empty if there is no telemetry file, stops at the first message that can't be read
+fn read_telemetry_timestamps(path: &Path) -> HashMap<u64, f64> {
+    let mut timestamps = HashMap::new();
+    let contents = fs::read(path).unwrap_or_default();
+    let mut buf = contents.as_slice();
+    while let Ok(telemetry) = TelemetryFrame::decode_length_delimited(&mut buf) {
+        timestamps.insert(telemetry.recording_index, telemetry.timestamp);
+    }
+    timestamps
+}
+
+impl FrameSource for ImageDirectory {
+    fn next_frame(&mut self) -> Option<Frame> {
+        puffin::profile_function!();
+
+        loop {
+            let path = self.files.get(self.next)?;
+            let index = self.next;
+            self.next += 1;
+
+            match imread(path.to_str().unwrap(), IMREAD_COLOR) {
+                Ok(image) if image.size().is_ok_and(|size| size.width > 0) => {
+                    return Some(Frame {
+                        image,
+                        timestamp: self.timestamp(path),
+                        index: index as u64,
+                    });
+                }
+                _ => println!("Could not read {}, skipping", path.display()),
+            }
+        }
+    }
+
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    fn seek(&mut self, index: u64) {
+        self.next = (index as usize).min(self.files.len());
+    }
+}
+
+// Generates frames of a grey floor with a blue and yellow line that sway side to side
+// For running without a camera or recording, the colours match the default config
+pub struct TestPattern {
+    index: u64,
+}
+
+const PATTERN_WIDTH: i32 = 640;
+const PATTERN_HEIGHT: i32 = 480;
+const PATTERN_FPS: f64 = 30.;
+
+impl TestPattern {
+    pub fn new() -> TestPattern {
+        TestPattern { index: 0 }
+    }
+}
+
+impl FrameSource for TestPattern {
+    fn next_frame(&mut self) -> Option<Frame> {
+        puffin::profile_function!();
+
+        let timestamp = self.index as f64 / PATTERN_FPS;
+        let mut image = Mat::new_rows_cols_with_default(
+            PATTERN_HEIGHT,
+            PATTERN_WIDTH,
+            CV_8UC3,
+            VecN::<f64, 4> { 0: [112., 118., 120., 0.] },
+        )
+        .unwrap();
+
+        // the far ends of the lines move so the track looks like it is curving
+        let sway = (timestamp * 0.5).sin() * 80.;
+        let far_y = PATTERN_HEIGHT / 5;
+        let lines = [
+            (60, 110, 266, 280, VecN::<f64, 4> { 0: [220., 162., 47., 0.] }),
+            (530, 580, 360, 374, VecN::<f64, 4> { 0: [65., 220., 85., 0.] }),
+        ];
+        for (near_left, near_right, far_left, far_right, colour) in lines {
+            let far_offset = sway as i32;
+            let polygon = Vector::<opencv::core::Point>::from_iter([
+                opencv::core::Point::new(near_left, PATTERN_HEIGHT),
+                opencv::core::Point::new(near_right, PATTERN_HEIGHT),
+                opencv::core::Point::new(far_right + far_offset, far_y),
+                opencv::core::Point::new(far_left + far_offset, far_y),
+            ]);
+            fill_convex_poly(&mut image, &polygon, colour, LineTypes::LINE_8.into(), 0).unwrap();
+        }
+
+        let index = self.index;
+        self.index += 1;
+        Some(Frame { image, timestamp, index })
+    }
+
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    fn seek(&mut self, index: u64) {
+        self.index = index;
+    }
+}
+
+pub fn display_image_and_get_key(_frame: &Mat, config: &Config) -> Option<i32> {
     if !SHOULD_DISPLAY_RAW_VIDEO {
-        return false;
+        return None;
     }
     // dont want to draw on actual image
     let mut frame = _frame.clone();
-    annotate_video(&mut frame);
+    annotate_video(&mut frame, config);
     if frame.size().unwrap().width > 0 {
         highgui::imshow("window", &frame).unwrap();
     }
 
+    wait_for_key()
+}
+
+// Waits a short time for a key press on the video windows, just waits if they aren't shown
+pub fn wait_for_key() -> Option<i32> {
+    if !SHOULD_DISPLAY_RAW_VIDEO {
+        thread::sleep(Duration::from_millis(20));
+        return None;
+    }
     let key = highgui::wait_key(20).unwrap();
     if key > 0 && key != 255 {
         println!("Got key press {key}");
-        return true;
+        return Some(key);
     }
-    return false;
+    None
 }
 
 #[derive(Default)]
@@ -97,32 +543,59 @@ pub struct Recorder {
     images: i32,
     blues: i32,
     yellows: i32,
-    needs: HashSet<String>
+    overlays: i32,
+    // behind a mutex so vision can record from multiple threads
+    needs: Mutex<HashSet<String>>,
+    // copies of every image offered this frame, for the black box
+    keep_frame_images: bool,
+    frame_images: Mutex<Vec<(String, Mat)>>,
 }
 
 impl Recorder {
     pub fn enqueue_images(&mut self, cmd: &messages::command::DriveCommand) {
+        let needs = self.needs.get_mut().unwrap();
         if self.images < cmd.images_frame as i32 {
             self.images += 1;
-            self.needs.insert("image".to_owned());
+            needs.insert(FRAME_IMAGE.to_owned());
         }
         if self.blues < cmd.images_blue as i32 {
             self.blues += 1;
-            self.needs.insert("blue".to_owned());
+            needs.insert("blue".to_owned());
         }
         if self.yellows < cmd.images_yellow as i32 {
             self.yellows += 1;
-            self.needs.insert("yellow".to_owned());
+            needs.insert("yellow".to_owned());
+        }
+        if self.overlays < cmd.images_overlay as i32 {
+            self.overlays += 1;
+            needs.insert("overlay".to_owned());
         }
     }
 
-    pub fn record_image(&mut self, img: &Mat, desc: &str) {
-        if self.needs.contains(desc) {
+    pub fn set_keep_frame_images(&mut self, keep: bool) {
+        self.keep_frame_images = keep;
+    }
+
+    // The images offered since this was last called
+    pub fn take_frame_images(&mut self) -> Vec<(String, Mat)> {
+        self.frame_images.get_mut().unwrap().drain(..).collect()
+    }
+
+    // For images that are expensive to make, so they are only made when they will be saved
+    pub fn is_needed(&self, desc: &str) -> bool {
+        self.needs.lock().unwrap().contains(desc)
+    }
+
+    pub fn record_image(&self, img: &Mat, desc: &str) {
+        // the black box keeps the whole frame itself
+        if self.keep_frame_images && desc != FRAME_IMAGE {
+            self.frame_images.lock().unwrap().push((desc.to_owned(), img.clone()));
+        }
+        if self.needs.lock().unwrap().remove(desc) {
             println!("taking image of {}", desc);
             let now = OffsetDateTime::now_utc();
             let params = Vector::<i32>::new(); // required arguemtn
             let _ = imwrite(format!("images/{}-{}.png", desc, now.to_string()).as_str(), img, &params);
-            self.needs.remove(desc);
         }
     }
 }
\ No newline at end of file
@@ -97,9 +97,11 @@ impl Logger for NetworkComms {
 fn accumulate_diagnostic_map(
     existing: &mut messages::diagnostic::FullDiagnostic, new: &messages::diagnostic::FullDiagnostic,
 ) {
-    // Replace the diagnostic and path with most recent
+    // Replace the diagnostic and path with most recent, a diagnostic on its own keeps the last path
     existing.diagnostic = new.diagnostic.clone();
-    existing.path = new.path.clone();
+    if new.path.is_some() {
+        existing.path = new.path.clone();
+    }
     // Accumulate map updates
     existing.map_update = match (&mut existing.map_update, &new.map_update) {
         (None, _) => new.map_update.clone(),
@@ -154,9 +156,8 @@ impl Commander for NetworkComms {
                 state: CommandMode::StateOff as i32,
                 throttle: 0.,
                 turn: 0.,
-                images_blue: data.last_recieved.images_blue,
-                images_yellow: data.last_recieved.images_yellow,
-                images_frame: data.last_recieved.images_frame,
+                // keep image counters so requests aren't repeated when the connection comes back
+                ..data.last_recieved.clone()
             };
         }
 
@@ -16,16 +16,167 @@ pub mod file {
         pub high: Vec<i32>,
     }
 
+    // Where the car's movement comes from
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+    pub enum OdometrySource {
+        // what the car was told to do, scaled by the fudge factors
+        Command,
+        // distance from the wheel encoder, turning from the command
+        Encoder,
+        // a kalman filter combining the command with the wheel encoder
+        Fusion,
+        // lining up the lines seen on each frame with the last, falling back to the command
+        Visual,
+    }
+
     #[derive(Serialize, Deserialize, Debug)]
     pub struct DriveConfig {
         pub odom_speed_fudge: f32,
         pub odom_turn_fudge: f32,
+        pub odometry: OdometrySource,
+        // BCM numbering
+        pub encoder_pin: u8,
+        // wheel travel for each encoder pulse, in meters
+        pub meters_per_pulse: f64,
     }
 
     #[derive(Serialize, Deserialize, Debug)]
     pub struct ContourConfig {
         pub min_boundry: i32,
         pub min_area_ratio: f32,
+        // distance between points along the outline on the ground, in meters, 0 or less gives no points
+        pub sample_spacing: f64,
+    }
+
+    // Which vision method is used to find each colour of line
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+    pub enum LineDetector {
+        // outlines of the thresholded colour
+        Contour,
+        // canny edges and hough line segments next to the colour
+        Edge,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct EdgeConfig {
+        pub blue_detector: LineDetector,
+        pub yellow_detector: LineDetector,
+        pub finish_detector: LineDetector,
+        pub canny_low: f64,
+        pub canny_high: f64,
+        pub hough_threshold: i32,
+        pub min_segment_length: f64,
+        pub max_segment_gap: f64,
+        // how far from an edge the line colour can be, in pixels
+        pub colour_search_radius: i32,
+        // fraction of a segment that has to be near the line colour to keep it
+        pub min_colour_fraction: f32,
+        // distance between points along a segment on the ground, in meters, 0 or less gives no points
+        pub sample_spacing: f64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct ArrowConfig {
+        pub model_path: String,
+        pub confidence_threshold: f32,
+        pub min_area: f64,
+        pub colour: ColourConfig,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct ObstacleConfig {
+        pub min_area: f64,
+        pub colour: ColourConfig,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct FinishConfig {
+        // how close the finish line has to be to be reported as ahead
+        pub ahead_distance: f64,
+        pub colour: ColourConfig,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct ImageConfig {
+        // rows removed from the top and bottom of the frame before running vision
+        pub top_crop: i32,
+        pub bottom_crop: i32,
+        // polygons of [x, y] points in full frame pixels, anything detected inside them is ignored
+        // e.g. the car's bumper or camera mount
+        pub exclude: Vec<Vec<Vec<i32>>>,
+    }
+
+    // Made up points for a lane line that can't be seen, using the one that can
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct LaneConfig {
+        pub single_line_fallback: bool,
+        // distance between the inside edges of the two lines, in meters
+        pub lane_width: f64,
+    }
+
+    // Lining up each frame's lines with the map to correct odometry drift
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct LocalizationConfig {
+        pub enabled: bool,
+        // corrections bigger than this on one frame are ignored, in meters and radians
+        pub max_correction_dist: f64,
+        pub max_correction_angle: f64,
+        // fewer line points than this nearby in the map isn't enough to line up against
+        pub min_map_points: usize,
+    }
+
+    // Adjusts the camera exposure in software to keep part of the image at a set brightness
+    // Only used when the camera's own auto exposure is off
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct SoftwareExposureConfig {
+        pub enabled: bool,
+        // average brightness to aim for, 0-255
+        pub target_brightness: f64,
+        // [x, y, width, height] in full frame pixels, where the tape usually is
+        pub region: [i32; 4],
+        // how much of the error is corrected each update, 0-1
+        pub rate: f64,
+        pub min_exposure: f64,
+        pub max_exposure: f64,
+    }
+
+    // Settings passed to the camera driver, so the colour thresholds don't change with the lighting
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct CameraConfig {
+        // let the camera pick exposure and gain itself, otherwise the values below are used
+        pub auto_exposure: bool,
+        pub exposure: f64,
+        pub gain: f64,
+        pub auto_white_balance: bool,
+        // colour temperature in kelvin, when auto white balance is off
+        pub white_balance: f64,
+        pub software_exposure: SoftwareExposureConfig,
+    }
+
+    // Recent frames kept in memory to save when something goes wrong
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct BlackBoxConfig {
+        pub enabled: bool,
+        // how far back to keep, in seconds. Full frames take about 1MB each
+        pub seconds: f64,
+    }
+
+    // Pinhole camera intrinsics for the full (uncropped) frame and opencv distortion coefficients
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct LensConfig {
+        pub camera_matrix: Vec<Vec<f64>>,
+        pub distortion: Vec<f64>,
+    }
+
+    // A rectangle on the floor with known corners, used by the calibrate mode
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct CalibrationConfig {
+        // (lateral, forward) position of the near left corner relative to the car
+        pub target_near_left: Vec<f32>,
+        pub target_width: f32,
+        pub target_length: f32,
+        // number of inner corners (columns, rows) of the checkerboard, which should span the target rectangle
+        pub board_inner_corners: Vec<i32>,
     }
 
     #[derive(Serialize, Deserialize, Debug)]
@@ -35,10 +186,21 @@ pub mod file {
         pub blue_colour: ColourConfig,
         pub contour_cfg: ContourConfig,
         pub drive_cfg: DriveConfig,
+        pub arrow_cfg: ArrowConfig,
+        pub obstacle_cfg: ObstacleConfig,
+        pub finish_cfg: FinishConfig,
+        pub calibration_cfg: CalibrationConfig,
+        pub lens_cfg: LensConfig,
+        pub image_cfg: ImageConfig,
+        pub edge_cfg: EdgeConfig,
+        pub lane_cfg: LaneConfig,
+        pub localization_cfg: LocalizationConfig,
+        pub black_box_cfg: BlackBoxConfig,
+        pub camera_cfg: CameraConfig,
     }
 
     pub enum LineColour {
-        YELLOW, BLUE
+        YELLOW, BLUE, FINISH
     }
 
     impl Config {
@@ -46,14 +208,26 @@ pub mod file {
             match col {
                 LineColour::BLUE => self.blue_colour.to_opencv_range(),
                 LineColour::YELLOW => self.yellow_colour.to_opencv_range(),
+                LineColour::FINISH => self.finish_cfg.colour.to_opencv_range(),
+            }
+        }
+
+        pub fn detector_for_line(&self, col: &LineColour) -> LineDetector {
+            match col {
+                LineColour::BLUE => self.edge_cfg.blue_detector,
+                LineColour::YELLOW => self.edge_cfg.yellow_detector,
+                LineColour::FINISH => self.edge_cfg.finish_detector,
             }
         }
     }
+    pub const CONFIG_FILE: &str = "config.json";
+
     pub struct ConfigReader<T> {
         last_edit_time: SystemTime,
         filename: String,
         reader: fn(&str) -> T,
         last_value: T,
+        version: u32,
     }
 
     const FILE_READ_MIN: Duration = Duration::from_millis(200);
@@ -65,9 +239,15 @@ pub mod file {
                 filename: filename.to_string(),
                 reader: reader,
                 last_value: Self::read_file(filename, reader),
+                version: 0,
             }
         }
 
+        // Incremented every time the file is re-read, so users can tell when to regenerate derived values
+        pub fn version(&self) -> u32 {
+            self.version
+        }
+
         fn get_last_edit_time(filename: &str) -> Result<SystemTime, io::Error>  {
             fs::metadata(filename).and_then(|metadata| metadata.modified())
         }
@@ -84,11 +264,17 @@ pub mod file {
                     thread::sleep(Duration::from_millis(100));
                     self.last_value = Self::read_file(self.filename.as_str(), self.reader);
                     self.last_edit_time = new_last_edit_time;
+                    self.version += 1;
                 }
             }
             &self.last_value
         }
 
+        // The value from the last call to get_value, without checking if the file has changed
+        pub fn get_last_value(&self) -> &T {
+            &self.last_value
+        }
+
         fn read_file(filename: &str, reader: fn(&str) -> T) -> T {
             println!("reading file");
             let file_contents = fs::read_to_string(filename).unwrap();
@@ -117,23 +303,9 @@ pub mod colours {
     }
 
     // pub const BLACK_MASK: ColourRange = r(c(110, 50, 100), c(120, 255, 255));
-    // pub const PURPLE_MASK: ColourRange = r(c(110, 50, 100), c(120, 255, 255));
     // pub const RED_MASK: ColourRange = r(c(110, 50, 100), c(120, 255, 255));
 }
 
-pub mod image {
-    use opencv::core::Rect;
-
-    pub const TOP_CROP: i32 = 90;
-    pub const BOTTOM_CROP: i32 = 30;
-    pub const EXCLUDE_RECT: Rect = Rect {
-        x: 0, //x: 195,
-        y: 360,
-        width: 640, // width: 270,
-        height: 120,
-    };
-}
-
 pub mod display {
     use super::is_running_on_pi;
 
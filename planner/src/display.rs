@@ -1,15 +1,112 @@
-use crate::config::image::{EXCLUDE_RECT, TOP_CROP};
+use crate::{
+    config::file::{Config, ConfigReader},
+    planner::Path,
+    points::{PointMap, PointType, Pos},
+    state::CarState,
+    vision::perspective::GroundProjector,
+};
 use opencv::{
-    core::{Mat, VecN},
-    imgproc::{line, rectangle},
+    core::{Mat, MatTraitConst, Point2f, VecN, Vector},
+    imgproc::{circle, line, polylines},
 };
 
-pub fn annotate_video(img: &mut Mat) {
+// Only things this close to the car are drawn on the overlay, further away the projection isn't accurate
+const OVERLAY_RANGE: f64 = 3.0;
+// Points closer than this are behind the camera's view
+const OVERLAY_MIN_FORWARD: f64 = 0.05;
+
+pub fn annotate_video(img: &mut Mat, config: &Config) {
+    let white = VecN::<f64, 4> {
+        0: [255., 255., 255., 0.],
+    };
+    let size = img.size().unwrap();
+    let image_cfg = &config.image_cfg;
+    for y in [image_cfg.top_crop, size.height - image_cfg.bottom_crop] {
+        let left_pnt = opencv::core::Point { x: 0, y };
+        let right_pnt = opencv::core::Point { x: size.width, y };
+        line(img, left_pnt, right_pnt, white, 3, opencv::imgproc::LineTypes::LINE_8.into(), 0).unwrap();
+    }
+    for polygon in &image_cfg.exclude {
+        let points = Vector::<opencv::core::Point>::from_iter(
+            polygon.iter().map(|p| opencv::core::Point { x: p[0], y: p[1] }),
+        );
+        polylines(img, &points, true, white, 3, opencv::imgproc::LineTypes::LINE_8.into(), 0).unwrap();
+    }
+}
+
+// BGR, roughly matching the client's colours
+fn colour_for_point(point_type: PointType) -> VecN<f64, 4> {
+    let bgr = match point_type {
+        PointType::LeftLine => [255., 0., 0.],
+        PointType::RightLine => [0., 255., 255.],
+        PointType::Obstacle => [255., 0., 255.],
+        PointType::ArrowLeft | PointType::ArrowRight => [255., 255., 255.],
+        PointType::FinishLine => [0., 255., 0.],
+    };
+    VecN::<f64, 4> {
+        0: [bgr[0], bgr[1], bgr[2], 0.],
+    }
+}
+
+// Global positions to the car's frame, None if they can't be seen by the camera
+fn to_car_frame(pos: Pos, state: &CarState) -> Option<Pos> {
+    let relative = (pos - state.pos).rotate(-state.angle);
+    if relative.x < OVERLAY_MIN_FORWARD || relative.dist(Pos { x: 0., y: 0. }) > OVERLAY_RANGE {
+        return None;
+    }
+    Some(relative)
+}
+
+// Full frame pixel position from a point in the cropped image
+fn to_frame_point(point: Point2f, top_crop: i32) -> opencv::core::Point {
+    opencv::core::Point {
+        x: point.x.round() as i32,
+        y: point.y.round() as i32 + top_crop,
+    }
+}
+
+// Draws the map points and planned path on a full camera frame, to check the projection lines up with what was seen
+pub fn draw_map_overlay(
+    img: &mut Mat, state: &CarState, point_map: &dyn PointMap, path: &Path, projector: &mut GroundProjector,
+    config: &ConfigReader<Config>,
+) -> Result<(), opencv::Error> {
+    puffin::profile_function!();
+
+    let top_crop = config.get_last_value().image_cfg.top_crop;
+
+    let visible: Vec<(Pos, PointType)> = point_map
+        .get_points_in_area(state.pos, OVERLAY_RANGE)
+        .iter()
+        .filter_map(|point| to_car_frame(point.pos, state).map(|pos| (pos, point.point_type)))
+        .collect();
+    let positions: Vec<Pos> = visible.iter().map(|(pos, _)| *pos).collect();
+    let image_points = projector.ground_to_image(&positions, config);
+    for (image_point, (_, point_type)) in image_points.iter().zip(visible.iter()) {
+        circle(
+            img,
+            to_frame_point(*image_point, top_crop),
+            4,
+            colour_for_point(*point_type),
+            -1,
+            opencv::imgproc::LineTypes::LINE_8.into(),
+            0,
+        )?;
+    }
+
+    let path_positions: Vec<Pos> = path
+        .points
+        .iter()
+        .filter_map(|point| to_car_frame(point.pos, state))
+        .collect();
+    let path_points = Vector::<opencv::core::Point>::from_iter(
+        projector
+            .ground_to_image(&path_positions, config)
+            .into_iter()
+            .map(|p| to_frame_point(p, top_crop)),
+    );
     let white = VecN::<f64, 4> {
         0: [255., 255., 255., 0.],
     };
-    let left_pnt = opencv::core::Point { x: 0, y: TOP_CROP };
-    let right_pnt = opencv::core::Point { x: 640, y: TOP_CROP };
-    rectangle(img, EXCLUDE_RECT, white, 3, opencv::imgproc::LineTypes::LINE_8.into(), 0).unwrap();
-    line(img, left_pnt, right_pnt, white, 3, opencv::imgproc::LineTypes::LINE_8.into(), 0).unwrap();
+    polylines(img, &path_points, false, white, 2, opencv::imgproc::LineTypes::LINE_8.into(), 0)?;
+    Ok(())
 }
@@ -1,4 +1,4 @@
-use crate::{config::{file::{Config, ConfigReader}, is_running_on_pi}, messages::path::SimpleDrive, odom::{BlindRelativeStateProvider, RelativeStateProvider}};
+use crate::{config::{file::{Config, ConfigReader}, is_running_on_pi}, messages::path::SimpleDrive, odom::RelativeStateProvider};
 use rppal::pwm::{Channel, Polarity, Pwm};
 use std::time::Duration;
 
@@ -6,22 +6,25 @@ use std::time::Duration;
 pub struct CarCommander {
     driver: Box<dyn Driver>,
     steerer: Box<dyn Steerer>,
-    state_provider: BlindRelativeStateProvider, // should be dyn
+    state_provider: Box<dyn RelativeStateProvider>,
 }
 
 impl CarCommander {
-    pub fn new() -> CarCommander {
+    pub fn new(state_provider: Box<dyn RelativeStateProvider>) -> CarCommander {
         CarCommander::new_from_components(
             Box::new(PwmDriver::new(PwmPinNumber::Pin12)),
             Box::new(PwmDriver::new(PwmPinNumber::Pin35)),
+            state_provider,
         )
     }
 
-    fn new_from_components(driver: Box<dyn Driver>, steerer: Box<dyn Steerer>) -> CarCommander {
+    fn new_from_components(
+        driver: Box<dyn Driver>, steerer: Box<dyn Steerer>, state_provider: Box<dyn RelativeStateProvider>,
+    ) -> CarCommander {
         CarCommander {
             driver: driver,
             steerer: steerer,
-            state_provider: BlindRelativeStateProvider::new(),
+            state_provider,
         }
     }
 
@@ -33,8 +36,8 @@ impl CarCommander {
         self.state_provider.set_command(command, config);
     }
 
-    pub fn get_state_provider(&self) -> &impl RelativeStateProvider {
-        &self.state_provider
+    pub fn get_state_provider(&mut self) -> &mut dyn RelativeStateProvider {
+        self.state_provider.as_mut()
     }
 }
 
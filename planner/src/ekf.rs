@@ -0,0 +1,309 @@
+// Fuses the command model with whatever sensors there are using an extended Kalman filter
+// The state is x, y, heading, speed and yaw rate in the filter's own frame, which starts at the origin facing along x
+use std::{
+    array,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::file::{Config, ConfigReader},
+    encoder::PulseCounter,
+    messages::path::SimpleDrive,
+    odom::{PoseCovariance, RelativeStateProvider},
+    points::Pos,
+    state::CarState,
+};
+
+const N: usize = 5;
+const X: usize = 0;
+const Y: usize = 1;
+const ANGLE: usize = 2;
+const SPEED: usize = 3;
+const YAW_RATE: usize = 4;
+
+type Matrix = [[f64; N]; N];
+
+// How much each part of the state can change without being told about it, variance per second
+const PROCESS_NOISE: [f64; N] = [0.001, 0.001, 0.002, 0.5, 1.0];
+// The car starts at the origin of the filter's frame so only speed and yaw rate are unknown
+const INITIAL_VARIANCE: [f64; N] = [0.0, 0.0, 0.0, 0.1, 0.1];
+// The command is only a rough guess of what the car does, so any sensor is trusted over it
+const COMMAND_SPEED_VARIANCE: f64 = 1.0;
+const COMMAND_CURVATURE_VARIANCE: f64 = 0.5;
+// Below this the yaw rate says nothing about the curvature, in m/s
+const MIN_SPEED_FOR_CURVATURE: f64 = 0.05;
+const WHEEL_SPEED_VARIANCE: f64 = 0.01;
+// How far off lining up with the map usually is, for x, y and heading
+const POSE_CORRECTION_VARIANCE: [f64; 3] = [0.0004, 0.0004, 0.0004];
+
+pub enum Measurement {
+    // speed from the wheels in m/s, the sign is taken from the command as encoders can't tell direction
+    WheelSpeed { speed: f64, variance: f64 },
+    // how fast the heading is changing in rad/s, positive turns the same way as positive curvature
+    YawRate { rate: f64, variance: f64 },
+    // where the car is in the filter's frame, from vision. Variance is for x, y and heading
+    Pose { pos: Pos, angle: f64, variance: [f64; 3] },
+}
+
+// Something that measures the car, read each time the filter steps
+pub trait MeasurementSource {
+    // Measurements since the last call
+    fn take_measurements(&mut self) -> Vec<Measurement>;
+}
+
+// Speed from a wheel encoder's pulses
+pub struct WheelSpeedSource {
+    counter: Box<dyn PulseCounter>,
+    meters_per_pulse: f64,
+    last_read: Instant,
+}
+
+impl WheelSpeedSource {
+    pub fn new(counter: Box<dyn PulseCounter>, meters_per_pulse: f64) -> WheelSpeedSource {
+        WheelSpeedSource {
+            counter,
+            meters_per_pulse,
+            last_read: Instant::now(),
+        }
+    }
+}
+
+impl MeasurementSource for WheelSpeedSource {
+    fn take_measurements(&mut self) -> Vec<Measurement> {
+        let elapsed = self.last_read.elapsed().as_secs_f64();
+        self.last_read = Instant::now();
+        if elapsed <= 0.0 {
+            return Vec::new();
+        }
+        let dist = self.counter.take_pulses() as f64 * self.meters_per_pulse;
+        vec![Measurement::WheelSpeed {
+            speed: dist / elapsed,
+            variance: WHEEL_SPEED_VARIANCE,
+        }]
+    }
+}
+
+pub struct EkfRelativeStateProvider {
+    state: [f64; N],
+    covariance: Matrix,
+    sources: Vec<Box<dyn MeasurementSource>>,
+    // measurements given directly rather than through a source, used on the next step
+    pending: Vec<Measurement>,
+    command_speed: f64,
+    command_curvature: f64,
+    // 1 going forwards, -1 backwards, kept from the last command that moved
+    direction: f64,
+    // pose when get_movement was last called, movement is reported relative to it
+    last_reported: (Pos, f64),
+    last_step: Instant,
+}
+
+impl RelativeStateProvider for EkfRelativeStateProvider {
+    fn set_command(&mut self, command: SimpleDrive, config: &mut ConfigReader<Config>) {
+        let drive_cfg = &config.get_value().drive_cfg;
+        self.command_speed = (command.speed * drive_cfg.odom_speed_fudge) as f64;
+        self.command_curvature = (command.curvature * drive_cfg.odom_turn_fudge) as f64;
+        if command.speed != 0.0 {
+            self.direction = command.speed.signum() as f64;
+        }
+    }
+
+    fn get_movement(&mut self) -> CarState {
+        puffin::profile_function!();
+
+        let elapsed = self.last_step.elapsed();
+        self.last_step = Instant::now();
+        self.movement_over(elapsed)
+    }
+
+    fn pose_covariance(&self) -> Option<PoseCovariance> {
+        // rotate x and y from the filter's frame into the car's
+        let (s, c) = (-self.state[ANGLE]).sin_cos();
+        let rotation = [[c, -s, 0.], [s, c, 0.], [0., 0., 1.]];
+        let pose: PoseCovariance = array::from_fn(|i| array::from_fn(|j| self.covariance[i][j]));
+        Some(mul(&mul(&rotation, &pose), &transpose(&rotation)))
+    }
+
+    fn add_pose_correction(&mut self, correction: &CarState) {
+        let (last_pos, last_angle) = self.last_reported;
+        let corrected = CarState {
+            pos: last_pos,
+            angle: last_angle,
+            ..Default::default()
+        } + *correction;
+        self.apply(Measurement::Pose {
+            pos: corrected.pos,
+            angle: corrected.angle,
+            variance: POSE_CORRECTION_VARIANCE,
+        });
+        // the car has already been moved by the whole correction but the filter only moves part of the way towards it,
+        // so the next movement is from where the filter ended up to not take any of the correction back
+        let pose = self.pose();
+        self.last_reported = (pose.pos, pose.angle);
+    }
+
+    fn reset(&mut self) {
+        let mut sources = std::mem::take(&mut self.sources);
+        for source in &mut sources {
+            source.take_measurements();
+        }
+        *self = EkfRelativeStateProvider::new(sources);
+    }
+
+    fn measures_speed(&self) -> bool {
+        !self.sources.is_empty()
+    }
+}
+
+impl EkfRelativeStateProvider {
+    pub fn new(sources: Vec<Box<dyn MeasurementSource>>) -> EkfRelativeStateProvider {
+        let mut covariance = [[0.; N]; N];
+        for (i, variance) in INITIAL_VARIANCE.iter().enumerate() {
+            covariance[i][i] = *variance;
+        }
+        EkfRelativeStateProvider {
+            state: [0.; N],
+            covariance,
+            sources,
+            pending: Vec::new(),
+            command_speed: 0.,
+            command_curvature: 0.,
+            direction: 1.,
+            last_reported: (Pos { x: 0., y: 0. }, 0.),
+            last_step: Instant::now(),
+        }
+    }
+
+    // For measurements that don't come from a source, like corrections from matching against the map
+    pub fn add_measurement(&mut self, measurement: Measurement) {
+        self.pending.push(measurement);
+    }
+
+    // Where the filter thinks the car is in its own frame
+    pub fn pose(&self) -> CarState {
+        CarState {
+            pos: Pos { x: self.state[X], y: self.state[Y] },
+            angle: self.state[ANGLE],
+            curvature: self.curvature(),
+            speed: self.state[SPEED],
+        }
+    }
+
+    // Steps the filter over the given time, split out so it can be tested without waiting
+    pub fn movement_over(&mut self, elapsed: Duration) -> CarState {
+        self.predict(elapsed.as_secs_f64());
+
+        // the command model, speed is what was asked for and the yaw rate should match the speed around the curve
+        self.update(unit(SPEED), self.command_speed - self.state[SPEED], COMMAND_SPEED_VARIANCE);
+        let mut h = unit(YAW_RATE);
+        h[SPEED] = -self.command_curvature;
+        let innovation = -(self.state[YAW_RATE] - self.state[SPEED] * self.command_curvature);
+        self.update(h, innovation, COMMAND_CURVATURE_VARIANCE);
+
+        let mut measurements: Vec<Measurement> = self.pending.drain(..).collect();
+        for source in &mut self.sources {
+            measurements.extend(source.take_measurements());
+        }
+        for measurement in measurements {
+            self.apply(measurement);
+        }
+
+        let pose = self.pose();
+        let (last_pos, last_angle) = self.last_reported;
+        self.last_reported = (pose.pos, pose.angle);
+        CarState {
+            pos: (pose.pos - last_pos).rotate(-last_angle),
+            angle: pose.angle - last_angle,
+            ..pose
+        }
+    }
+
+    fn curvature(&self) -> f64 {
+        if self.state[SPEED].abs() < MIN_SPEED_FOR_CURVATURE {
+            self.command_curvature
+        } else {
+            self.state[YAW_RATE] / self.state[SPEED]
+        }
+    }
+
+    fn predict(&mut self, dt: f64) {
+        let (sin, cos) = self.state[ANGLE].sin_cos();
+        let speed = self.state[SPEED];
+        self.state[X] += speed * cos * dt;
+        self.state[Y] += speed * sin * dt;
+        self.state[ANGLE] += self.state[YAW_RATE] * dt;
+
+        // jacobian of the motion model
+        let mut f = identity();
+        f[X][ANGLE] = -speed * sin * dt;
+        f[X][SPEED] = cos * dt;
+        f[Y][ANGLE] = speed * cos * dt;
+        f[Y][SPEED] = sin * dt;
+        f[ANGLE][YAW_RATE] = dt;
+
+        self.covariance = mul(&mul(&f, &self.covariance), &transpose(&f));
+        for (i, noise) in PROCESS_NOISE.iter().enumerate() {
+            self.covariance[i][i] += noise * dt;
+        }
+    }
+
+    fn apply(&mut self, measurement: Measurement) {
+        match measurement {
+            Measurement::WheelSpeed { speed, variance } => {
+                let speed = speed.abs() * self.direction;
+                self.update(unit(SPEED), speed - self.state[SPEED], variance);
+            }
+            Measurement::YawRate { rate, variance } => {
+                self.update(unit(YAW_RATE), rate - self.state[YAW_RATE], variance);
+            }
+            Measurement::Pose { pos, angle, variance } => {
+                self.update(unit(X), pos.x - self.state[X], variance[0]);
+                self.update(unit(Y), pos.y - self.state[Y], variance[1]);
+                self.update(unit(ANGLE), wrap_angle(angle - self.state[ANGLE]), variance[2]);
+            }
+        }
+    }
+
+    // Kalman update for a single measurement, h is how the measurement depends on the state
+    fn update(&mut self, h: [f64; N], innovation: f64, variance: f64) {
+        let ph: [f64; N] = array::from_fn(|i| dot(&self.covariance[i], &h));
+        let s = dot(&h, &ph) + variance;
+        if s <= 0. {
+            return;
+        }
+
+        for (value, ph_i) in self.state.iter_mut().zip(ph) {
+            *value += ph_i / s * innovation;
+        }
+        // covariance is symmetric so H P is the same as P H transposed
+        for (row, ph_i) in self.covariance.iter_mut().zip(ph) {
+            for (value, ph_j) in row.iter_mut().zip(ph) {
+                *value -= ph_i * ph_j / s;
+            }
+        }
+    }
+}
+
+fn wrap_angle(angle: f64) -> f64 {
+    (angle + std::f64::consts::PI).rem_euclid(std::f64::consts::TAU) - std::f64::consts::PI
+}
+
+fn unit(index: usize) -> [f64; N] {
+    array::from_fn(|i| if i == index { 1. } else { 0. })
+}
+
+fn dot(a: &[f64; N], b: &[f64; N]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn identity() -> Matrix {
+    array::from_fn(unit)
+}
+
+fn mul<const M: usize>(a: &[[f64; M]; M], b: &[[f64; M]; M]) -> [[f64; M]; M] {
+    array::from_fn(|i| array::from_fn(|j| (0..M).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+fn transpose<const M: usize>(a: &[[f64; M]; M]) -> [[f64; M]; M] {
+    array::from_fn(|i| array::from_fn(|j| a[j][i]))
+}
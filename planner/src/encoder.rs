@@ -0,0 +1,73 @@
+use crate::config::is_running_on_pi;
+use rppal::gpio::{Gpio, InputPin, Trigger};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+// Counts pulses from a wheel encoder
+pub trait PulseCounter {
+    // Pulses since the last call
+    fn take_pulses(&mut self) -> u32;
+}
+
+// Counts rising edges on a GPIO pin with an interrupt, so pulses aren't missed while the main loop is busy
+pub struct GpioPulseCounter {
+    // kept so the interrupt stays registered
+    _pin: Option<InputPin>,
+    count: Arc<AtomicU32>,
+}
+
+impl GpioPulseCounter {
+    pub fn new(bcm_pin: u8) -> GpioPulseCounter {
+        let count = Arc::new(AtomicU32::new(0));
+        let pin = Self::open_pin(bcm_pin, Arc::clone(&count));
+
+        GpioPulseCounter {
+            _pin: if is_running_on_pi() {
+                Some(pin.unwrap())
+            } else {
+                pin.ok()
+            },
+            count,
+        }
+    }
+
+    fn open_pin(bcm_pin: u8, count: Arc<AtomicU32>) -> rppal::gpio::Result<InputPin> {
+        // https://docs.rs/rppal/latest/rppal/gpio/index.html
+        let mut pin = Gpio::new()?.get(bcm_pin)?.into_input_pullup();
+        pin.set_async_interrupt(Trigger::RisingEdge, move |_| {
+            count.fetch_add(1, Ordering::Relaxed);
+        })?;
+        Ok(pin)
+    }
+}
+
+impl PulseCounter for GpioPulseCounter {
+    fn take_pulses(&mut self) -> u32 {
+        self.count.swap(0, Ordering::Relaxed)
+    }
+}
+
+// Pulses are added by hand, for tests and running without the car
+// Clones share the same count, so one can be kept to add pulses after the other is given away
+#[derive(Clone, Default)]
+pub struct SimulatedPulseCounter {
+    count: Arc<AtomicU32>,
+}
+
+impl SimulatedPulseCounter {
+    pub fn new() -> SimulatedPulseCounter {
+        SimulatedPulseCounter::default()
+    }
+
+    pub fn add_pulses(&self, pulses: u32) {
+        self.count.fetch_add(pulses, Ordering::Relaxed);
+    }
+}
+
+impl PulseCounter for SimulatedPulseCounter {
+    fn take_pulses(&mut self) -> u32 {
+        self.count.swap(0, Ordering::Relaxed)
+    }
+}
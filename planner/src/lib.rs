@@ -0,0 +1,32 @@
+// Split out from main so the integration tests can use the vision pipeline
+pub mod calibrate;
+pub mod camera;
+pub mod comms;
+pub mod config;
+pub mod display;
+pub mod driver;
+pub mod ekf;
+pub mod encoder;
+pub mod follower;
+pub mod localization;
+pub mod logging;
+pub mod planner;
+pub mod playback;
+pub mod points;
+pub mod pruner;
+pub mod recording;
+pub mod state;
+pub mod vision;
+pub mod visual_odom;
+pub mod odom;
+pub mod messages {
+    pub mod path {
+        include!(concat!(env!("OUT_DIR"), "/messages.path.rs"));
+    }
+    pub mod diagnostic {
+        include!(concat!(env!("OUT_DIR"), "/messages.diagnostic.rs"));
+    }
+    pub mod command {
+        include!(concat!(env!("OUT_DIR"), "/messages.commands.rs"));
+    }
+}
@@ -0,0 +1,85 @@
+// Corrects where the car thinks it is by lining up the lines seen on each frame with the lines already in the map,
+// so odometry drift doesn't leave offset copies of the same tape in the map
+use std::collections::HashMap;
+
+use crate::{
+    config::file::LocalizationConfig,
+    odom::PoseCovariance,
+    points::{Point, PointMap, PointType, Pos},
+    state::CarState,
+    visual_odom::align_points,
+};
+
+// The map has a copy of each line from every frame, points closer than this are merged before matching
+const THIN_CELL: f64 = 0.03;
+// Map points this much further out than the furthest line point seen are matched against, in meters
+const SEARCH_MARGIN: f64 = 0.2;
+// Corrections more than this many standard deviations of odometry's uncertainty are rejected
+const COVARIANCE_GATE: f64 = 3.0;
+
+// Moves the car and the points found on this frame to where the lines best match the map
+// Returns the correction that was applied relative to the car, None if there wasn't enough to match
+// The covariance is how unsure odometry is of where the car is, when it's sure only smaller corrections are let through
+pub fn correct_state(
+    points: &mut [Point], state: &mut CarState, point_map: &dyn PointMap, config: &LocalizationConfig,
+    covariance: Option<PoseCovariance>,
+) -> Option<CarState> {
+    puffin::profile_function!();
+
+    if !config.enabled {
+        return None;
+    }
+    let seen: Vec<Pos> = points.iter().filter(|p| is_line(p)).map(|p| p.pos).collect();
+    if seen.is_empty() {
+        return None;
+    }
+    let center = seen.iter().fold(Pos { x: 0.0, y: 0.0 }, |sum, p| sum + *p) * (1.0 / seen.len() as f64);
+    let radius = seen.iter().map(|p| p.dist(center)).fold(0.0, f64::max) + SEARCH_MARGIN;
+
+    let old_state = *state;
+    let to_car = |pos: Pos| (pos - old_state.pos).rotate(-old_state.angle);
+    let map_points: Vec<Point> = point_map.get_points_in_area(center, radius);
+    let map = thin(map_points.iter().filter(|p| is_line(p)).map(|p| to_car(p.pos)));
+    if map.len() < config.min_map_points {
+        return None;
+    }
+
+    let current: Vec<Pos> = seen.into_iter().map(to_car).collect();
+    let found = align_points(&map, &current, CarState::default())?;
+    // a bigger jump is more likely lining up with the wrong line than the car having drifted that far
+    let (max_dist, max_angle) = match covariance {
+        Some(covariance) => (
+            config.max_correction_dist.min(COVARIANCE_GATE * (covariance[0][0] + covariance[1][1]).sqrt()),
+            config.max_correction_angle.min(COVARIANCE_GATE * covariance[2][2].sqrt()),
+        ),
+        None => (config.max_correction_dist, config.max_correction_angle),
+    };
+    if found.pos.dist(Pos { x: 0.0, y: 0.0 }) > max_dist || found.angle.abs() > max_angle {
+        return None;
+    }
+    let correction = CarState {
+        pos: found.pos,
+        angle: found.angle,
+        ..old_state
+    };
+
+    *state = old_state + correction;
+    for point in points.iter_mut() {
+        point.pos = to_car(point.pos).rotate(state.angle) + state.pos;
+    }
+    Some(correction)
+}
+
+fn is_line(point: &Point) -> bool {
+    !point.is_virtual && (point.point_type == PointType::LeftLine || point.point_type == PointType::RightLine)
+}
+
+// One point for each small square that has any points in it
+fn thin(points: impl Iterator<Item = Pos>) -> Vec<Pos> {
+    let mut cells = HashMap::new();
+    for point in points {
+        let cell = ((point.x / THIN_CELL).floor() as i64, (point.y / THIN_CELL).floor() as i64);
+        cells.entry(cell).or_insert(point);
+    }
+    cells.into_values().collect()
+}
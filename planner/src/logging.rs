@@ -39,9 +39,12 @@ pub trait Logger {
                     points::PointType::ArrowLeft => messages::path::PointType::ArrowLeft,
                     points::PointType::ArrowRight => messages::path::PointType::ArrowRight,
                     points::PointType::Obstacle => messages::path::PointType::Obstacle,
+                    points::PointType::FinishLine => messages::path::PointType::FinishLine,
                 }
                 .into(),
                 id: p.id,
+                is_virtual: p.is_virtual,
+                confidence: p.confidence as f32,
             })
             .collect();
         let map_update_dto = Some(messages::path::MapUpdate {
@@ -57,6 +60,14 @@ pub trait Logger {
             diagnostic: diagnostic_dto,
         });
     }
+
+    // For when no frame was processed, such as while playback is paused
+    fn send_diagnostic(&mut self, diagnostic: &messages::diagnostic::Diagnostic) {
+        self.send_core(&messages::diagnostic::FullDiagnostic {
+            diagnostic: Some(diagnostic.clone()),
+            ..Default::default()
+        });
+    }
 }
 
 pub struct FileLogger {
@@ -1,67 +1,80 @@
-mod camera;
-mod comms;
-mod config;
-mod display;
-mod driver;
-mod follower;
-mod logging;
-mod planner;
-mod points;
-mod pruner;
-mod state;
-mod vision;
-mod odom;
-mod messages {
-    pub mod path {
-        include!(concat!(env!("OUT_DIR"), "/messages.path.rs"));
-    }
-    pub mod diagnostic {
-        include!(concat!(env!("OUT_DIR"), "/messages.diagnostic.rs"));
-    }
-    pub mod command {
-        include!(concat!(env!("OUT_DIR"), "/messages.commands.rs"));
-    }
-}
+use opencv::{highgui, Result};
+use planner::{
+    calibrate,
+    camera::{display_image_and_get_key, open_frame_source, wait_for_key, Frame, Recorder},
+    comms::{Commander, NetworkComms},
+    config::{self, display::SHOULD_DISPLAY_RAW_VIDEO, file::{Config, ConfigReader, CONFIG_FILE}},
+    display,
+    driver::CarCommander,
+    follower::Follower,
+    localization,
+    logging::Logger,
+    messages::{
+        command::CommandMode,
+        diagnostic::{Diagnostic, PoseCorrection},
+        path::SimpleDrive,
+    },
+    odom,
+    planner::Planner,
+    playback::{Playback, PlaybackStep},
+    points::{GridPointMap, PointMap, Pos},
+    pruner,
+    recording::{self, BlackBox, RunRecorder},
+    state::CarState,
+    vision::{perspective::GroundProjector, Vision},
+};
+use std::{
+    collections::VecDeque,
+    env, thread,
+    time::{Duration, Instant},
+};
 
-use camera::{Capture, Recorder};
-use comms::{Commander, NetworkComms};
-use config::file::{Config, ConfigReader};
-use driver::CarCommander;
-use follower::Follower;
-use logging::Logger;
-use messages::{command::CommandMode, diagnostic::Diagnostic, path::SimpleDrive};
-use odom::RelativeStateProvider;
-use opencv::Result;
-use planner::Planner;
-use points::{GridPointMap, PointMap, Pos};
-use state::CarState;
-use std::{collections::VecDeque, env, time::Instant};
-use vision::Vision;
+// How long to wait before looking for a frame again when the camera hasn't got a new one
+const NO_FRAME_WAIT: Duration = Duration::from_millis(1);
+// How many frames back playback can step without clearing the map
+const STEP_BACK_FRAMES: usize = 300;
 
 fn main() -> Result<()> {
     let args = env::args().skip(1).collect::<Vec<String>>();
+    let mut config = ConfigReader::new(CONFIG_FILE, |s| serde_json::from_str::<Config>(s).unwrap());
+
+    if args.first().is_some_and(|arg| arg == "calibrate") {
+        let mut frames = open_frame_source(args.get(1));
+        return calibrate::run_calibration(frames.as_mut(), &mut config, CONFIG_FILE);
+    }
+    if args.first().is_some_and(|arg| arg == "calibrate-lens") {
+        let image_dir = args.get(1).map_or("images", |dir| dir.as_str());
+        return calibrate::run_lens_calibration(image_dir, &mut config, CONFIG_FILE);
+    }
+
     // Create objects
-    let mut camera = match args.first() {
-        None => Capture::camera(),
-        Some(filename) => Capture::video(filename),
-    };
+    // camera, video file, directory of pngs or test pattern
+    let mut frames = open_frame_source(args.first());
     let point_map = &mut GridPointMap::new() as &mut dyn PointMap;
     let mut vision = Vision::new();
     let planner = Planner::new();
     let follower = Follower::new();
-    let mut driver = CarCommander::new();
+    let mut driver = CarCommander::new(odom::state_provider_from_config(config.get_value()));
     let mut network_comms = NetworkComms::new();
-    let mut config = ConfigReader::new("config.json", |s| serde_json::from_str::<Config>(s).unwrap());
     let mut recorder = Recorder::default();
+    let mut overlay_projector = GroundProjector::new();
+    let mut run_recorder = RunRecorder::new();
+    let mut black_box = BlackBox::new();
+    // recordings can be paused, stepped and seeked, the camera just plays
+    let mut playback = Playback::new(frames.can_seek());
+    // config version the camera settings were last applied from
+    let mut camera_settings_version = None;
 
     // Initialise state
-    let mut current_state = CarState::default();
-    current_state.angle = -3.141 / 2.;
-    current_state.pos = Pos { x: 0.0, y: 0.0 };
+    let mut current_state = initial_state();
 
     let mut last_frame = Instant::now();
     let mut frame_times = VecDeque::new();
     frame_times.push_back(0.0);
+    // resent with the playback state while no frames are being read
+    let mut last_diagnostic = Diagnostic::default();
+    // where the car was before each recent frame was read, so stepping back can carry on from there
+    let mut states_before: VecDeque<(u64, CarState)> = VecDeque::new();
 
     let server_addr = format!("127.0.0.1:{}", puffin_http::DEFAULT_PORT);
     let _puffin_server = puffin_http::Server::new(&server_addr).unwrap();
@@ -71,19 +84,90 @@ fn main() -> Result<()> {
     loop {
         puffin::GlobalProfiler::lock().new_frame();
 
-        let frame = match camera.get_frame() {
+        let network_command = network_comms.get_latest_message();
+        playback.handle_command(&network_command.playback);
+
+        match playback.next_step() {
+            PlaybackStep::Next => {}
+            PlaybackStep::Seek(index) => {
+                // the map and position were built from frames that are no longer the ones before this
+                frames.seek(index);
+                current_state = initial_state();
+                point_map.remove(&|_| false);
+                driver.get_state_provider().reset();
+                states_before.clear();
+            }
+            PlaybackStep::Back(index) => {
+                // the map is kept to look at, only the car goes back, or everything if it's too far back
+                frames.seek(index);
+                states_before.retain(|(read, _)| *read <= index);
+                match states_before.pop_back() {
+                    Some((read, state)) if read == index => current_state = state,
+                    _ => {
+                        current_state = initial_state();
+                        point_map.remove(&|_| false);
+                        states_before.clear();
+                    }
+                }
+                driver.get_state_provider().reset();
+            }
+            PlaybackStep::Hold => {
+                last_diagnostic.playback_paused = playback.is_paused();
+                network_comms.send_diagnostic(&last_diagnostic);
+                if wait_for_key().is_some_and(|key| playback.handle_key(key)) {
+                    return Ok(());
+                }
+                continue;
+            }
+        }
+
+        let frame = match frames.next_frame() {
             Some(x) => x,
+            None if frames.can_seek() => {
+                playback.end_of_source();
+                last_diagnostic.playback_paused = playback.is_paused();
+                network_comms.send_diagnostic(&last_diagnostic);
+                continue;
+            }
+            None if !frames.has_finished() => {
+                // the camera hasn't captured a new frame yet
+                thread::sleep(NO_FRAME_WAIT);
+                continue;
+            }
             None => return Ok(()),
         };
+        playback.frame_read(&frame);
+        states_before.push_back((frame.index, current_state));
+        if states_before.len() > STEP_BACK_FRAMES {
+            states_before.pop_front();
+        }
+        if display_image_and_get_key(&frame.image, config.get_value()).is_some_and(|key| playback.handle_key(key)) {
+            return Ok(());
+        }
+        if camera_settings_version != Some(config.version()) {
+            frames.apply_camera_settings(&config.get_value().camera_cfg);
+            camera_settings_version = Some(config.version());
+        }
 
         let movement = driver.get_state_provider().get_movement();
         current_state += movement;
 
-        let network_command = network_comms.get_latest_message();
-
         recorder.enqueue_images(&network_command);
+        let black_box_cfg = &config.get_value().black_box_cfg;
+        let (black_box_enabled, black_box_seconds) = (black_box_cfg.enabled, black_box_cfg.seconds);
+        recorder.set_keep_frame_images(black_box_enabled);
 
-        let new_points = vision.get_points_from_image(&frame, current_state, &mut config, point_map, &mut recorder);
+        let mut new_points =
+            vision.get_points_from_image(&frame.image, current_state, &mut config, point_map, &recorder);
+        // how the lines moved since the last frame, then where they are against the map
+        driver.get_state_provider().add_frame_points(&mut new_points, &mut current_state, frame.timestamp);
+        let localization_cfg = &config.get_value().localization_cfg;
+        let covariance = driver.get_state_provider().pose_covariance();
+        let correction =
+            localization::correct_state(&mut new_points, &mut current_state, point_map, localization_cfg, covariance);
+        if let Some(correction) = &correction {
+            driver.get_state_provider().add_pose_correction(correction);
+        }
 
         point_map.add_points(&new_points);
 
@@ -91,7 +175,17 @@ fn main() -> Result<()> {
 
         let path = planner.find_path(current_state, point_map);
 
-        let command = match CommandMode::try_from(network_command.state).unwrap_or_default() {
+        if SHOULD_DISPLAY_RAW_VIDEO || recorder.is_needed("overlay") {
+            let mut overlay = frame.image.clone();
+            display::draw_map_overlay(&mut overlay, &current_state, point_map, &path, &mut overlay_projector, &config)?;
+            if SHOULD_DISPLAY_RAW_VIDEO {
+                highgui::imshow("overlay", &overlay)?;
+            }
+            recorder.record_image(&overlay, "overlay");
+        }
+
+        let mode = CommandMode::try_from(network_command.state).unwrap_or_default();
+        let command = match mode {
             CommandMode::StateAuto => follower.command_to_follow_path(&path),
             CommandMode::StateManual => SimpleDrive {
                 speed: network_command.throttle,
@@ -103,15 +197,32 @@ fn main() -> Result<()> {
             },
         };
 
+        let telemetry = recording::telemetry_frame(&frame, current_state, &command, mode);
+        run_recorder.set_enabled(network_command.record, &frame);
+        run_recorder.record(&frame, telemetry.clone());
+        if black_box_enabled {
+            black_box.push(&frame, recorder.take_frame_images(), telemetry, black_box_seconds);
+            let speed_measured = driver.get_state_provider().measures_speed();
+            black_box.check_triggers(&network_command, mode, current_state, &command, speed_measured);
+        }
+
         driver.drive(command, &mut config);
 
+        let finish_line_ahead = point_map
+            .get_finish_line_ahead(&current_state, config.get_value().finish_cfg.ahead_distance)
+            .is_some();
 
-        network_comms.send(
-            &path,
-            &new_points,
-            &point_map.get_last_removed_ids(),
-            &get_diagnostic(&frame_times, current_state),
+        last_diagnostic = get_diagnostic(
+            &frame_times,
+            current_state,
+            finish_line_ahead,
+            frames.dropped_frames(),
+            run_recorder.is_recording(),
+            &frame,
+            playback.is_paused(),
+            correction,
         );
+        network_comms.send(&path, &new_points, &point_map.get_last_removed_ids(), &last_diagnostic);
 
         frame_times.push_front(last_frame.elapsed().as_secs_f32());
         if frame_times.len() > 10 {
@@ -121,15 +232,36 @@ fn main() -> Result<()> {
     }
 }
 
-fn get_diagnostic(frame_times: &VecDeque<f32>, state: CarState) -> Diagnostic {
+fn initial_state() -> CarState {
+    let mut state = CarState::default();
+    state.angle = -3.141 / 2.;
+    state.pos = Pos { x: 0.0, y: 0.0 };
+    state
+}
+
+fn get_diagnostic(
+    frame_times: &VecDeque<f32>, state: CarState, finish_line_ahead: bool, dropped_frames: u64, recording: bool,
+    frame: &Frame, playback_paused: bool, correction: Option<CarState>,
+) -> Diagnostic {
     puffin::profile_function!();
 
     let frametime_avg = frame_times.clone().iter().sum::<f32>() / frame_times.len() as f32;
     let frametime_max = frame_times.clone().into_iter().reduce(f32::max).unwrap();
     Diagnostic {
+        // measured when the wheel encoder is used, otherwise what was commanded
         actual_speed: state.speed as f32,
         actual_turn: state.curvature as f32,
         framerate_avg: if frametime_avg != 0.0 { 1.0 / frametime_avg } else { 0.0 },
         framerate_90: if frametime_max != 0.0 { 1.0 / frametime_max } else { 0.0 },
+        finish_line_ahead,
+        dropped_frames,
+        recording,
+        frame_index: frame.index,
+        playback_paused,
+        localization: correction.map(|correction| PoseCorrection {
+            x: correction.pos.x as f32,
+            y: correction.pos.y as f32,
+            angle: correction.angle as f32,
+        }),
     }
 }
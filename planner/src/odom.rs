@@ -1,10 +1,47 @@
 use std::{collections::VecDeque, time::{Duration, Instant}};
 
-use crate::{config::file::{Config, ConfigReader}, messages::path::SimpleDrive, points::Pos, state::CarState};
+use crate::{
+    config::file::{Config, ConfigReader, OdometrySource},
+    ekf::{EkfRelativeStateProvider, WheelSpeedSource},
+    encoder::{GpioPulseCounter, PulseCounter},
+    messages::path::SimpleDrive,
+    points::{Point, Pos},
+    state::CarState,
+    visual_odom::VisualRelativeStateProvider,
+};
 
 
+// Variance of x, y and heading and how they vary together, with x forward and y to the right of the car
+pub type PoseCovariance = [[f64; 3]; 3];
+
+// Tracks how the car moved, speed and curvature in the returned state are what the car is doing now
 pub trait RelativeStateProvider {
-    fn get_movement(&self) -> CarState;
+    // Called with each command sent to the car
+    fn set_command(&mut self, command: SimpleDrive, config: &mut ConfigReader<Config>);
+    // Movement since the last frame, relative to the car
+    fn get_movement(&mut self) -> CarState;
+
+    // How unsure the provider is of where the car is, None if it doesn't keep track
+    fn pose_covariance(&self) -> Option<PoseCovariance> {
+        None
+    }
+
+    // Points vision found on this frame and the state they were placed with, for providers that use them
+    // Called before the points go in the map, so the provider can move the car and the points with it
+    // The timestamp is when the frame was captured, in seconds
+    fn add_frame_points(&mut self, _points: &mut [Point], _state: &mut CarState, _timestamp: f64) {}
+
+    // Called when something else moved the car, such as lining up with the map
+    // The correction is relative to the car and has already been applied to its state
+    fn add_pose_correction(&mut self, _correction: &CarState) {}
+
+    // Forgets everything from before, for when the frames jump somewhere else like seeking a recording
+    fn reset(&mut self) {}
+
+    // If the speed comes from a sensor rather than just what the car was told to do
+    fn measures_speed(&self) -> bool {
+        false
+    }
 }
 
 pub struct CommandInTime {
@@ -20,7 +57,33 @@ pub struct BlindRelativeStateProvider {
 }
 
 impl RelativeStateProvider for BlindRelativeStateProvider {
-    fn get_movement(&self) -> CarState {
+    fn set_command(&mut self, command: SimpleDrive, config: &mut ConfigReader<Config>) {
+        self.commands_queue.push_back(CommandInTime { time: Instant::now(), command });
+
+        loop {
+            let front = self.commands_queue.front();
+            if front.is_none() || front.is_some_and(|cmd| cmd.time.elapsed() < self.delay) {
+                break;
+            }
+            self.commands_queue.pop_front();
+        }
+
+        let front_op = self.commands_queue.front();
+        if let Some(front) = front_op {
+            let cfg_val = config.get_value();
+            let current_result = SimpleDrive {
+                curvature: front.command.curvature * cfg_val.drive_cfg.odom_turn_fudge,
+                speed: front.command.speed * cfg_val.drive_cfg.odom_speed_fudge,
+            };
+            let alpha = 0.1;
+            self.avg = SimpleDrive {
+                curvature: self.avg.curvature * (1.0-alpha) + current_result.curvature * alpha,
+                speed: self.avg.speed * (1.0-alpha) + current_result.speed * alpha,
+            };
+        }
+    }
+
+    fn get_movement(&mut self) -> CarState {
         puffin::profile_function!();
 
         let first = self.commands_queue.get(0);
@@ -35,6 +98,11 @@ impl RelativeStateProvider for BlindRelativeStateProvider {
             speed: self.avg.speed as f64,
         }.step_time(cmd_for)
     }
+
+    fn reset(&mut self) {
+        self.commands_queue.clear();
+        self.avg = SimpleDrive { curvature: 0.0, speed: 0.0 };
+    }
 }
 
 impl BlindRelativeStateProvider {
@@ -45,30 +113,94 @@ impl BlindRelativeStateProvider {
             avg: SimpleDrive { curvature: 0.0, speed: 0.0 },
         }
     }
+}
 
-    pub fn set_command(&mut self, command: SimpleDrive, config: &mut ConfigReader<Config>) {
-        self.commands_queue.push_back(CommandInTime { time: Instant::now(), command });
+// The state provider picked in the config
+pub fn state_provider_from_config(config: &Config) -> Box<dyn RelativeStateProvider> {
+    let drive_cfg = &config.drive_cfg;
+    match drive_cfg.odometry {
+        OdometrySource::Command => Box::new(BlindRelativeStateProvider::new()),
+        OdometrySource::Encoder => Box::new(EncoderRelativeStateProvider::new(
+            Box::new(GpioPulseCounter::new(drive_cfg.encoder_pin)),
+            drive_cfg.meters_per_pulse,
+        )),
+        OdometrySource::Fusion => Box::new(EkfRelativeStateProvider::new(vec![Box::new(WheelSpeedSource::new(
+            Box::new(GpioPulseCounter::new(drive_cfg.encoder_pin)),
+            drive_cfg.meters_per_pulse,
+        ))])),
+        OdometrySource::Visual => {
+            Box::new(VisualRelativeStateProvider::new(Box::new(BlindRelativeStateProvider::new())))
+        }
+    }
+}
 
-        loop {
-            let front = self.commands_queue.front();
-            if front.is_none() || front.is_some_and(|cmd| cmd.time.elapsed() < self.delay) {
-                break;
-            }
-            self.commands_queue.pop_front();
+// How much of each new speed measurement goes into the reported speed
+const ENCODER_SPEED_ALPHA: f64 = 0.3;
+
+// Measures how far the car went with a wheel encoder, the turn still comes from the command
+// The encoder only has one channel so which way the wheels turn comes from the command too
+pub struct EncoderRelativeStateProvider {
+    counter: Box<dyn PulseCounter>,
+    meters_per_pulse: f64,
+    last_read: Instant,
+    curvature: f64,
+    // 1 going forwards, -1 backwards, kept from the last command that moved so coasting counts the right way
+    direction: f64,
+    speed: f64,
+}
+
+impl RelativeStateProvider for EncoderRelativeStateProvider {
+    fn set_command(&mut self, command: SimpleDrive, config: &mut ConfigReader<Config>) {
+        self.curvature = (command.curvature * config.get_value().drive_cfg.odom_turn_fudge) as f64;
+        if command.speed != 0.0 {
+            self.direction = command.speed.signum() as f64;
         }
+    }
 
-        let front_op = self.commands_queue.front();
-        if let Some(front) = front_op {
-            let cfg_val = config.get_value();
-            let current_result = SimpleDrive {
-                curvature: front.command.curvature * cfg_val.drive_cfg.odom_turn_fudge,
-                speed: front.command.speed * cfg_val.drive_cfg.odom_speed_fudge,
-            };
-            let alpha = 0.1;
-            self.avg = SimpleDrive {
-                curvature: self.avg.curvature * (1.0-alpha) + current_result.curvature * alpha,
-                speed: self.avg.speed * (1.0-alpha) + current_result.speed * alpha,
-            };
+    fn get_movement(&mut self) -> CarState {
+        puffin::profile_function!();
+
+        let elapsed = self.last_read.elapsed();
+        self.last_read = Instant::now();
+        self.movement_over(elapsed)
+    }
+
+    fn reset(&mut self) {
+        self.counter.take_pulses();
+        self.last_read = Instant::now();
+        self.speed = 0.0;
+    }
+
+    fn measures_speed(&self) -> bool {
+        true
+    }
+}
+
+impl EncoderRelativeStateProvider {
+    pub fn new(counter: Box<dyn PulseCounter>, meters_per_pulse: f64) -> EncoderRelativeStateProvider {
+        EncoderRelativeStateProvider {
+            counter,
+            meters_per_pulse,
+            last_read: Instant::now(),
+            curvature: 0.0,
+            direction: 1.0,
+            speed: 0.0,
+        }
+    }
+
+    // Movement from the pulses counted over the given time, split out so it can be tested without waiting
+    pub fn movement_over(&mut self, elapsed: Duration) -> CarState {
+        let dist = self.counter.take_pulses() as f64 * self.meters_per_pulse * self.direction;
+        if elapsed > Duration::ZERO {
+            let measured = dist / elapsed.as_secs_f64();
+            self.speed = self.speed * (1.0 - ENCODER_SPEED_ALPHA) + measured * ENCODER_SPEED_ALPHA;
         }
+
+        CarState {
+            pos: Pos { x: 0., y: 0. },
+            angle: 0.,
+            curvature: self.curvature,
+            speed: self.speed,
+        }.step_distance(dist)
     }
 }
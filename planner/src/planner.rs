@@ -29,7 +29,8 @@ mod distance_calculators {
         let edge_dist = state.pos.dist(point.pos);
 
         // goes from max_weight when at the edge to 0 when at EDGE_MAX_DIST away from edge
-        let weighting = (EDGE_MAX_DIST - edge_dist) / EDGE_MAX_DIST * EDGE_MAX_WEIGHT;
+        // scaled by confidence so it can get closer to points that are less likely to be a real edge
+        let weighting = (EDGE_MAX_DIST - edge_dist) / EDGE_MAX_DIST * EDGE_MAX_WEIGHT * point.confidence;
         if weighting >= 0.0 {
             weighting
         } else {
@@ -63,7 +64,7 @@ mod distance_calculators {
         };
         let unweighted = (angle_diff.signum() == good_direction) as i32 as f64;
 
-        unweighted * 0.5
+        unweighted * 0.5 * arrow.confidence
     }
 
     // Weight to make it take paths with smoother/less turning
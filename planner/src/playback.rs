@@ -0,0 +1,180 @@
+// Controls for replaying a recording, from the video window keys or the client:
+//   space    - pause/play
+//   .        - step forward one frame
+//   ,        - step back one frame, keeping the map built so far
+//   + / -    - double/halve the playback speed
+//   digits g - seek to the typed frame number, just g goes back to the start
+//   q/esc    - quit
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{camera::Frame, messages::command::PlaybackCommand};
+
+const MIN_SPEED: f64 = 0.05;
+const MAX_SPEED: f64 = 8.0;
+
+// What the main loop should do with the frame source
+pub enum PlaybackStep {
+    // read the next frame as normal
+    Next,
+    // go to this frame then read it, everything that depends on earlier frames should be reset
+    Seek(u64),
+    // go back to this frame then read it, keeping the map so it can still be looked at
+    Back(u64),
+    // don't read a frame, just keep checking for input
+    Hold,
+}
+
+pub struct Playback {
+    // only recordings can be controlled, the camera always plays
+    enabled: bool,
+    paused: bool,
+    speed: f64,
+    // index of the last frame read
+    current: Option<u64>,
+    pending: Option<PlaybackStep>,
+    // digits typed before g
+    typed_frame: String,
+    // the client's command the last time it was looked at, so only changes are acted on
+    // None until the first one, as the client's counters carry on from before the planner started
+    last_command: Option<PlaybackCommand>,
+    // when the last frame was read and its timestamp, for keeping to the playback speed
+    last_read: Option<(Instant, f64)>,
+}
+
+impl Playback {
+    pub fn new(enabled: bool) -> Playback {
+        Playback {
+            enabled,
+            paused: false,
+            speed: 1.0,
+            current: None,
+            pending: None,
+            typed_frame: String::new(),
+            last_command: None,
+            last_read: None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Returns true if the key means quit
+    pub fn handle_key(&mut self, key: i32) -> bool {
+        let key = char::from_u32(key as u32).unwrap_or_default();
+        if key == 'q' || key == '\u{1b}' {
+            return true;
+        }
+        if !self.enabled {
+            return false;
+        }
+
+        match key {
+            ' ' => self.set_paused(!self.paused),
+            '.' => self.step(1),
+            ',' => self.step(-1),
+            '+' | '=' => self.set_speed(self.speed * 2.),
+            '-' => self.set_speed(self.speed / 2.),
+            '0'..='9' => self.typed_frame.push(key),
+            'g' => {
+                let frame = self.typed_frame.parse().unwrap_or(0);
+                self.typed_frame.clear();
+                self.seek(frame);
+            }
+            _ => {}
+        }
+        false
+    }
+
+    // Acts on anything that changed since the last command from the client
+    pub fn handle_command(&mut self, command: &Option<PlaybackCommand>) {
+        let command = match command {
+            Some(command) if self.enabled => command.clone(),
+            _ => return,
+        };
+        let last = match self.last_command.replace(command.clone()) {
+            Some(last) => last,
+            None => return,
+        };
+
+        for _ in last.pause_toggles..command.pause_toggles {
+            self.set_paused(!self.paused);
+        }
+        if command.speed != last.speed && command.speed > 0. {
+            self.set_speed(command.speed as f64);
+        }
+        for _ in last.steps_forward..command.steps_forward {
+            self.step(1);
+        }
+        for _ in last.steps_back..command.steps_back {
+            self.step(-1);
+        }
+        if command.seeks > last.seeks {
+            self.seek(command.seek_frame);
+        }
+    }
+
+    // When the source runs out, stay on the last frame until told to go somewhere else
+    pub fn end_of_source(&mut self) {
+        if !self.paused {
+            println!("end of recording, paused");
+        }
+        self.paused = true;
+    }
+
+    pub fn next_step(&mut self) -> PlaybackStep {
+        if !self.enabled {
+            return PlaybackStep::Next;
+        }
+        match self.pending.take() {
+            Some(step) => step,
+            None if self.paused => PlaybackStep::Hold,
+            None => PlaybackStep::Next,
+        }
+    }
+
+    // Called with each frame read, waits so recordings play back at the chosen speed
+    pub fn frame_read(&mut self, frame: &Frame) {
+        self.current = Some(frame.index);
+        if !self.enabled {
+            return;
+        }
+
+        if let Some((read_at, timestamp)) = self.last_read {
+            let wanted = Duration::from_secs_f64(((frame.timestamp - timestamp) / self.speed).clamp(0., 1.));
+            let elapsed = read_at.elapsed();
+            if !self.paused && wanted > elapsed {
+                thread::sleep(wanted - elapsed);
+            }
+        }
+        self.last_read = Some((Instant::now(), frame.timestamp));
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        println!("{}", if paused { "paused" } else { "playing" });
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        println!("playback speed {:.2}x", self.speed);
+    }
+
+    fn step(&mut self, frames: i64) {
+        self.paused = true;
+        self.pending = Some(match (frames, self.current) {
+            (1, _) => PlaybackStep::Next,
+            (_, Some(current)) => PlaybackStep::Back((current as i64 + frames).max(0) as u64),
+            (_, None) => PlaybackStep::Seek(0),
+        });
+    }
+
+    fn seek(&mut self, frame: u64) {
+        println!("seeking to frame {}", frame);
+        self.pending = Some(PlaybackStep::Seek(frame));
+        self.last_read = None;
+    }
+}
@@ -1,9 +1,11 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{self, Display},
     ops,
 };
 
+use crate::state::CarState;
+
 #[derive(Copy, Clone, PartialEq, Default, Debug)]
 pub struct Pos {
     pub x: f64,
@@ -30,6 +32,16 @@ impl ops::Sub for Pos {
     }
 }
 
+impl ops::Mul<f64> for Pos {
+    type Output = Pos;
+    fn mul(self, rhs: f64) -> Self::Output {
+        Pos {
+            x: self.x * rhs,
+            y: self.y * rhs,
+        }
+    }
+}
+
 impl Pos {
     pub fn dist(&self, other: Pos) -> f64 {
         let dx = self.x - other.x;
@@ -64,13 +76,14 @@ impl Pos {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum PointType {
     LeftLine,
     RightLine,
     Obstacle,
     ArrowLeft,
     ArrowRight,
+    FinishLine,
 }
 
 // https://stackoverflow.com/a/32712140
@@ -100,12 +113,20 @@ pub struct Point {
     pub expire_at: f64,
     pub point_type: PointType,
     pub id: PointID,
+    // made up from the other line when only one is visible, rather than seen
+    pub is_virtual: bool,
+    // how much the finder trusts the point, from 0 to 1
+    pub confidence: f64,
 }
 
-pub trait PointMap {
+pub trait PointMap: Sync {
     fn get_nearest_point(&self, around: Pos) -> Option<Point>;
     fn get_count_in_area(&self, around: Pos) -> u32;
+    // All points of any type within max_dist
+    fn get_points_in_area(&self, around: Pos, max_dist: f64) -> Vec<Point>;
     fn get_arrow_points(&self) -> Vec<Point>;
+    // Distance to the nearest finish line point in front of the car, if there is one within max_dist
+    fn get_finish_line_ahead(&self, state: &CarState, max_dist: f64) -> Option<f64>;
     fn add_points(&mut self, points: &Vec<Point>);
     fn remove(&mut self, predicate: &dyn Fn(&Point) -> bool);
     fn get_last_removed_ids(&mut self) -> Vec<PointID>;
@@ -131,6 +152,7 @@ impl GridIndex {
 pub struct GridPointMap {
     grid: HashMap<GridIndex, Vec<Point>>,
     arrow_points: Vec<Point>,
+    finish_points: Vec<Point>,
     removed_ids: Vec<PointID>,
 }
 
@@ -139,11 +161,12 @@ impl GridPointMap {
         GridPointMap {
             grid: HashMap::new(),
             arrow_points: Vec::new(),
+            finish_points: Vec::new(),
             removed_ids: Vec::new(),
         }
     }
 
-    fn get_points_in_area(&self, around: Pos, max_dist: f64) -> Vec<Point> {
+    fn get_grid_points_in_area(&self, around: Pos, max_dist: f64) -> Vec<Point> {
         let top_left = GridIndex::from_pos(
             around
                 + Pos {
@@ -181,7 +204,7 @@ impl PointMap for GridPointMap {
         puffin::profile_function!();
         for grid_squares in 1..5 {
             let dist = grid_squares as f64 * GRID_SIZE;
-            let points = self.get_points_in_area(around, dist);
+            let points = self.get_grid_points_in_area(around, dist);
             let nearest = points.iter().reduce(|accum: &Point, new: &Point| {
                 if new.point_type.is_obstacle() && new.pos.dist(around) < accum.pos.dist(around) {
                     return new;
@@ -204,18 +227,50 @@ impl PointMap for GridPointMap {
     }
 
 
+    fn get_points_in_area(&self, around: Pos, max_dist: f64) -> Vec<Point> {
+        let mut result = self.get_grid_points_in_area(around, max_dist);
+        result.extend(
+            self.arrow_points
+                .iter()
+                .chain(self.finish_points.iter())
+                .filter(|point| point.pos.dist(around) < max_dist)
+                .cloned(),
+        );
+        result
+    }
+
     fn get_arrow_points(&self) -> Vec<Point> {
         self.arrow_points.clone()
     }
 
+    fn get_finish_line_ahead(&self, state: &CarState, max_dist: f64) -> Option<f64> {
+        puffin::profile_function!();
+
+        self.finish_points
+            .iter()
+            .filter(|p| (p.pos - state.pos).rotate(-state.angle).x > 0.0)
+            .map(|p| p.pos.dist(state.pos))
+            .filter(|dist| *dist < max_dist)
+            .reduce(f64::min)
+    }
+
     fn add_points(&mut self, points: &Vec<Point>) {
         puffin::profile_function!();
 
+        // virtual points are only a guess until the line they stand in for is seen again
+        let seen: HashSet<PointType> = points.iter().filter(|p| !p.is_virtual).map(|p| p.point_type).collect();
+        if !seen.is_empty() {
+            self.remove(&|p| !(p.is_virtual && seen.contains(&p.point_type)));
+        }
+
         for point in points.clone().into_iter() {
             match point.point_type {
                 PointType::ArrowLeft | PointType::ArrowRight => {
                     self.arrow_points.push(point);
                 }
+                PointType::FinishLine => {
+                    self.finish_points.push(point);
+                }
                 _ => {
                     let key = GridIndex::from_pos(point.pos);
                     self.grid.entry(key).or_default().push(point);
@@ -228,6 +283,7 @@ impl PointMap for GridPointMap {
         puffin::profile_function!();
 
         filter_with_removed(&mut self.arrow_points, predicate, &mut self.removed_ids);
+        filter_with_removed(&mut self.finish_points, predicate, &mut self.removed_ids);
 
         for points in self.grid.values_mut() {
             filter_with_removed(points, predicate, &mut self.removed_ids);
@@ -36,10 +36,13 @@ impl Pruner {
     }
 
     // Get the value the line finder will set expire_at to for new points
-    pub fn get_point_expiry(&mut self, pos: Pos, point_map: &dyn PointMap) -> f64 {
+    // Points the finder is less sure of are kept for less time
+    pub fn get_point_expiry(&mut self, pos: Pos, confidence: f64, point_map: &dyn PointMap) -> f64 {
         let count_in_grid = point_map.get_count_in_area(pos) as f32;
         let jitter = self.rng.sample(self.dist);
-        let keep_for = Duration::from_secs_f32(rescale(count_in_grid, 0.0, 100.0, 0.1, 0.05) * jitter);
+        let confidence_scale = rescale(confidence as f32, 0.0, 1.0, 0.5, 1.5);
+        let keep_for =
+            Duration::from_secs_f32(rescale(count_in_grid, 0.0, 100.0, 0.1, 0.05) * jitter * confidence_scale);
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
         (now + keep_for).as_secs_f64()
     }
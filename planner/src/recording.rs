@@ -0,0 +1,258 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+use opencv::{
+    core::{Mat, MatTraitConst, Vector},
+    imgcodecs::imwrite,
+    videoio::{VideoWriter, VideoWriterTrait, VideoWriterTraitConst},
+};
+use prost::Message;
+use time::OffsetDateTime;
+
+use crate::{
+    camera::Frame,
+    messages::{
+        command::{CommandMode, DriveCommand},
+        diagnostic::TelemetryFrame,
+        path::SimpleDrive,
+    },
+    state::CarState,
+};
+
+// Lossless so replaying a run gives vision exactly the same frames
+const RECORDING_FOURCC: [char; 4] = ['F', 'F', 'V', '1'];
+const RECORDING_FPS: f64 = 30.0;
+
+struct Recording {
+    video: VideoWriter,
+    telemetry: File,
+    // frames written so far, which is the index of the next frame in the video file
+    frames: u64,
+}
+
+// Records the raw camera frames of a run to a video with a sidecar of what the planner did on each frame
+// The sidecar is length delimited TelemetryFrame messages, one per video frame in the same order
+pub struct RunRecorder {
+    // what was last asked for, so failing to start isn't retried every frame
+    enabled: bool,
+    recording: Option<Recording>,
+}
+
+impl RunRecorder {
+    pub fn new() -> RunRecorder {
+        RunRecorder {
+            enabled: false,
+            recording: None,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // Starts or stops recording, a new pair of files is made each time it starts
+    pub fn set_enabled(&mut self, enabled: bool, frame: &Frame) {
+        if enabled == self.enabled {
+            return;
+        }
+        self.enabled = enabled;
+        self.recording = if enabled { start_recording(frame) } else { None };
+        if !enabled {
+            println!("stopped recording");
+        }
+    }
+
+    pub fn record(&mut self, frame: &Frame, mut telemetry: TelemetryFrame) {
+        puffin::profile_function!();
+
+        let recording = match &mut self.recording {
+            Some(recording) => recording,
+            None => return,
+        };
+        if let Err(e) = recording.video.write(&frame.image) {
+            println!("error writing recording video {}", e);
+            return;
+        }
+
+        telemetry.recording_index = recording.frames;
+        if let Err(e) = recording.telemetry.write_all(&telemetry.encode_length_delimited_to_vec()) {
+            println!("error writing recording telemetry {}", e);
+        }
+        recording.frames += 1;
+    }
+}
+
+// What the planner did on a frame, recording_index is filled in when it is written
+pub fn telemetry_frame(frame: &Frame, state: CarState, command: &SimpleDrive, mode: CommandMode) -> TelemetryFrame {
+    TelemetryFrame {
+        recording_index: 0,
+        source_index: frame.index,
+        timestamp: frame.timestamp,
+        x: state.pos.x as f32,
+        y: state.pos.y as f32,
+        angle: state.angle as f32,
+        speed: state.speed as f32,
+        curvature: state.curvature as f32,
+        command: Some(command.clone()),
+        mode: mode.into(),
+    }
+}
+
+fn start_recording(frame: &Frame) -> Option<Recording> {
+    let now = OffsetDateTime::now_utc();
+    let name = format!("run_{now}");
+    let size = frame.image.size().unwrap_or_default();
+    let [c1, c2, c3, c4] = RECORDING_FOURCC;
+    let fourcc = VideoWriter::fourcc(c1, c2, c3, c4).unwrap();
+
+    let video = match VideoWriter::new(&format!("{name}.avi"), fourcc, RECORDING_FPS, size, true) {
+        Ok(video) if video.is_opened().unwrap_or_default() => video,
+        _ => {
+            println!("could not open {name}.avi for recording");
+            return None;
+        }
+    };
+    let telemetry = match File::create(format!("{name}.telemetry")) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("could not create {name}.telemetry for recording {}", e);
+            return None;
+        }
+    };
+    println!("recording to {name}");
+
+    Some(Recording {
+        video,
+        telemetry,
+        frames: 0,
+    })
+}
+
+// Below this the car counts as stopped, in m/s
+const STOPPED_SPEED: f64 = 0.05;
+// Above this the car should be moving, in m/s
+const MOVING_COMMAND_SPEED: f32 = 0.2;
+// How long the car has to be stopped while it should be moving to trigger a dump
+const UNEXPECTED_STOP_TIME: Duration = Duration::from_millis(500);
+
+struct BlackBoxEntry {
+    captured_at: Instant,
+    frame: Mat,
+    // the masks and other images vision offered to the Recorder on this frame
+    images: Vec<(String, Mat)>,
+    telemetry: TelemetryFrame,
+}
+
+// Keeps the last few seconds of frames, vision images and telemetry in memory so they can be saved after
+// something goes wrong, when the client asks or automatically when the car is turned off or stops unexpectedly
+pub struct BlackBox {
+    entries: VecDeque<BlackBoxEntry>,
+    dumps: u32,
+    last_mode: CommandMode,
+    stopped_since: Option<Instant>,
+    // only dump once for each unexpected stop
+    dumped_stop: bool,
+}
+
+impl BlackBox {
+    pub fn new() -> BlackBox {
+        BlackBox {
+            entries: VecDeque::new(),
+            dumps: 0,
+            last_mode: CommandMode::StateOff,
+            stopped_since: None,
+            dumped_stop: false,
+        }
+    }
+
+    pub fn push(&mut self, frame: &Frame, images: Vec<(String, Mat)>, telemetry: TelemetryFrame, keep_for: f64) {
+        puffin::profile_function!();
+
+        let now = Instant::now();
+        self.entries.push_back(BlackBoxEntry {
+            captured_at: now,
+            frame: frame.image.clone(),
+            images,
+            telemetry,
+        });
+        while self
+            .entries
+            .front()
+            .is_some_and(|entry| (now - entry.captured_at).as_secs_f64() > keep_for)
+        {
+            self.entries.pop_front();
+        }
+    }
+
+    // Dumps the buffer if the client asked for it or something looks wrong
+    // Stopping is only noticed when the speed is measured, the command model always goes as fast as it is told
+    pub fn check_triggers(
+        &mut self, cmd: &DriveCommand, mode: CommandMode, state: CarState, command: &SimpleDrive, speed_measured: bool,
+    ) {
+        let mut reason = None;
+
+        if self.dumps < cmd.black_box_dumps {
+            self.dumps = cmd.black_box_dumps;
+            reason = Some("requested");
+        }
+
+        if mode == CommandMode::StateOff && self.last_mode != CommandMode::StateOff {
+            reason = Some("turned off");
+        }
+        self.last_mode = mode;
+
+        let should_be_moving = speed_measured && mode == CommandMode::StateAuto && command.speed > MOVING_COMMAND_SPEED;
+        if should_be_moving && state.speed.abs() < STOPPED_SPEED {
+            let stopped_since = *self.stopped_since.get_or_insert_with(Instant::now);
+            if stopped_since.elapsed() > UNEXPECTED_STOP_TIME && !self.dumped_stop {
+                self.dumped_stop = true;
+                reason = Some("stopped unexpectedly");
+            }
+        } else {
+            self.stopped_since = None;
+            self.dumped_stop = false;
+        }
+
+        if let Some(reason) = reason {
+            self.dump(reason);
+        }
+    }
+
+    // Writes everything in the buffer on another thread so the main loop isn't held up
+    fn dump(&mut self, reason: &str) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let now = OffsetDateTime::now_utc();
+        let dir = format!("blackbox_{now}");
+        println!("dumping {} frames to {dir}, {reason}", self.entries.len());
+
+        let entries: Vec<BlackBoxEntry> = self.entries.drain(..).collect();
+        thread::spawn(move || {
+            if let Err(e) = write_black_box(&dir, entries) {
+                println!("error writing black box {}", e);
+            }
+        });
+    }
+}
+
+fn write_black_box(dir: &str, entries: Vec<BlackBoxEntry>) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut telemetry_file = File::create(format!("{dir}/telemetry"))?;
+    let params = Vector::<i32>::new();
+    for (i, mut entry) in entries.into_iter().enumerate() {
+        let _ = imwrite(&format!("{dir}/frame-{i:04}.png"), &entry.frame, &params);
+        for (desc, image) in &entry.images {
+            let _ = imwrite(&format!("{dir}/{desc}-{i:04}.png"), image, &params);
+        }
+        entry.telemetry.recording_index = i as u64;
+        telemetry_file.write_all(&entry.telemetry.encode_length_delimited_to_vec())?;
+    }
+    println!("finished writing {dir}");
+    Ok(())
+}
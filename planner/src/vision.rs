@@ -1,28 +1,37 @@
 mod arrow;
-mod lines;
+mod edges;
+mod lane;
+pub mod lines;
 mod mock;
 mod obstacle;
 pub mod perspective;
 
 use crate::{
-    camera::Recorder, config::{file::{Config, ConfigReader, LineColour}, image::{BOTTOM_CROP, TOP_CROP}}, points::{Point, PointMap, PointType}, state::CarState
+    camera::{Recorder, FRAME_IMAGE}, config::file::{Config, ConfigReader, LineColour}, points::{Point, PointMap, PointType}, state::CarState
 };
 use opencv::{
     core::{BorderTypes, Mat, MatTraitConst, Rect, Size},
     imgproc::{cvt_color, gaussian_blur, ColorConversionCodes},
 };
+use rayon::{iter::{IntoParallelRefMutIterator, ParallelIterator}, ThreadPool, ThreadPoolBuilder};
 
-use self::{arrow::ArrowFinder, lines::LineFinder};
+use self::{arrow::ArrowFinder, edges::EdgeFinder, lines::LineFinder, obstacle::ObstacleFinder};
 
-pub trait ObjectFinder {
+// Finders are run in parallel, so anything they keep between frames is only accessed from one thread at a time
+pub trait ObjectFinder: Send {
     fn get_points(
-        &mut self, image: &opencv::core::Mat, state: &CarState, config: &mut ConfigReader<Config>,
-        point_map: &dyn PointMap, recorder: &mut Recorder
+        &mut self, image: &opencv::core::Mat, state: &CarState, config: &ConfigReader<Config>,
+        point_map: &dyn PointMap, recorder: &Recorder
     ) -> Result<Vec<Point>, opencv::Error>;
 }
 
+// Leaves a core for the main loop on the pi
+const VISION_THREADS: usize = 3;
+
 pub struct Vision {
-    point_finders: Vec<Box<dyn ObjectFinder>>,
+    // named so each shows up as its own scope in the profiler
+    point_finders: Vec<(&'static str, Box<dyn ObjectFinder>)>,
+    pool: ThreadPool,
     cropped: Mat,
     hsv: Mat,
     blurred: Mat,
@@ -30,17 +39,30 @@ pub struct Vision {
 
 impl Vision {
     pub fn new() -> Vision {
-        let mut point_finders: Vec<Box<dyn ObjectFinder>> = Vec::new();
-        point_finders.push(Box::new(LineFinder::new(PointType::LeftLine, LineColour::BLUE, "blue".to_owned())));
-        point_finders.push(Box::new(LineFinder::new(PointType::RightLine, LineColour::YELLOW, "yellow".to_owned())));
-        // point_finders.push(Box::new(ObstacleFinder::new(PointType::Obstacle, colours::PURPLE_MASK)));
-        // point_finders.push(Box::new(ObstacleFinder::new(PointType::Obstacle, colours::PURPLE_RED)));
-        point_finders.push(Box::new(ArrowFinder::new()));
+        let mut point_finders: Vec<(&'static str, Box<dyn ObjectFinder>)> = Vec::new();
+        point_finders.push((
+            "blue",
+            Box::new(LineFinder::new(PointType::LeftLine, LineColour::BLUE, "blue".to_owned())),
+        ));
+        point_finders.push((
+            "yellow",
+            Box::new(LineFinder::new(PointType::RightLine, LineColour::YELLOW, "yellow".to_owned())),
+        ));
+        point_finders.push(("blue edges", Box::new(EdgeFinder::new(PointType::LeftLine, LineColour::BLUE))));
+        point_finders.push(("yellow edges", Box::new(EdgeFinder::new(PointType::RightLine, LineColour::YELLOW))));
+        point_finders.push(("obstacle", Box::new(ObstacleFinder::new(PointType::Obstacle))));
+        point_finders.push((
+            "finish",
+            Box::new(LineFinder::new(PointType::FinishLine, LineColour::FINISH, "finish".to_owned())),
+        ));
+        point_finders.push(("finish edges", Box::new(EdgeFinder::new(PointType::FinishLine, LineColour::FINISH))));
+        point_finders.push(("arrow", Box::new(ArrowFinder::new())));
 
-        // point_finders.push(Box::new(FakePointProvider {}));
+        // point_finders.push(("fake", Box::new(FakePointProvider {})));
 
         return Vision {
             point_finders: point_finders,
+            pool: ThreadPoolBuilder::new().num_threads(VISION_THREADS).build().unwrap(),
             cropped: Mat::default(),
             hsv: Mat::default(),
             blurred: Mat::default(),
@@ -50,18 +72,25 @@ impl Vision {
     // Runs all the vision modules that give their output in map points
     pub fn get_points_from_image(
         &mut self, image: &opencv::core::Mat, state: CarState, config: &mut ConfigReader<Config>,
-        point_map: &dyn PointMap, recorder: &mut Recorder
+        point_map: &dyn PointMap, recorder: &Recorder
     ) -> Vec<Point> {
         puffin::profile_function!();
 
+        {
+            // the whole frame, so saved frames can be played back or used for calibration
+            puffin::profile_scope!("save image frame");
+            recorder.record_image(image, FRAME_IMAGE);
+        }
+
         {
             puffin::profile_scope!("crop");
             let size = image.size().unwrap();
+            let image_cfg = &config.get_value().image_cfg;
             let roi = Rect {
                 x: 0,
-                y: TOP_CROP,
+                y: image_cfg.top_crop,
                 width: size.width,
-                height: size.height - TOP_CROP - BOTTOM_CROP,
+                height: size.height - image_cfg.top_crop - image_cfg.bottom_crop,
             };
             let cropped_result = image.apply_1(roi);
             if let Ok(cropped) = cropped_result {
@@ -87,19 +116,26 @@ impl Vision {
             .unwrap();
         }
 
-        {
-            puffin::profile_scope!("save image frame");
-            recorder.record_image(&self.blurred, "image");
-        }
-
         {
             puffin::profile_scope!("hsv");
             cvt_color(&self.blurred, &mut self.hsv, ColorConversionCodes::COLOR_BGR2HSV.into(), 0).unwrap();
         }
 
-        self.point_finders
-            .iter_mut()
-            .flat_map(|finder| finder.get_points(&self.hsv, &state, config, point_map, recorder).unwrap())
-            .collect()
+        // finders only read the config, it was reloaded if needed when cropping
+        let config: &ConfigReader<Config> = config;
+        let hsv = &self.hsv;
+        // collect keeps the finder order so the output is the same as running them one after another
+        let points_per_finder: Vec<Vec<Point>> = self.pool.install(|| {
+            self.point_finders
+                .par_iter_mut()
+                .map(|(name, finder)| {
+                    puffin::profile_scope!("finder", *name);
+                    finder.get_points(hsv, &state, config, point_map, recorder).unwrap()
+                })
+                .collect()
+        });
+        let mut points: Vec<Point> = points_per_finder.into_iter().flatten().collect();
+        lane::add_missing_line(&mut points, &state, &config.get_last_value().lane_cfg);
+        points
     }
 }
@@ -1,19 +1,208 @@
+use opencv::{
+    core::{extract_channel, in_range, Mat, MatTraitConst, Rect, Size},
+    imgproc::{self, find_contours, resize, ContourApproximationModes, InterpolationFlags, RetrievalModes},
+    types::VectorOfVectorOfPoint,
+};
+use tract_onnx::prelude::*;
+
 use crate::{
-    camera::Recorder, config::file::{Config, ConfigReader}, points::{Point, PointMap}, state::CarState, vision::ObjectFinder
+    camera::Recorder, config::file::{ArrowConfig, Config, ConfigReader}, points::{Point, PointMap, PointType}, pruner::Pruner, state::CarState, vision::{perspective::{convert_point_relative_to_global, GroundProjector}, ObjectFinder}
 };
 
-pub struct ArrowFinder {}
+// Size of the square crop the model was trained on in scripts/arrow_model.py
+const MODEL_INPUT_SIDE: i32 = 50;
+const MODEL_INPUT_LEN: usize = (MODEL_INPUT_SIDE * MODEL_INPUT_SIDE) as usize;
+
+type ArrowModel = TypedRunnableModel<TypedModel>;
+
+// Output classes in the order keras' LabelEncoder gives them (alphabetical folder names)
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ArrowClass {
+    Left,
+    None,
+    Right,
+}
+
+impl ArrowClass {
+    fn from_index(index: usize) -> Option<ArrowClass> {
+        match index {
+            0 => Some(ArrowClass::Left),
+            1 => Some(ArrowClass::None),
+            2 => Some(ArrowClass::Right),
+            _ => None,
+        }
+    }
+
+    fn point_type(&self) -> Option<PointType> {
+        match self {
+            ArrowClass::Left => Some(PointType::ArrowLeft),
+            ArrowClass::Right => Some(PointType::ArrowRight),
+            ArrowClass::None => None,
+        }
+    }
+}
+
+// Finds dark blobs that could be arrows and classifies them with the onnx model exported from arrow_model.py
+pub struct ArrowFinder {
+    model: Option<ArrowModel>,
+    // path the current model was loaded from, so it can be reloaded when the config changes
+    model_path: String,
+    pruner: Pruner,
+    projector: GroundProjector,
+    // stored between frames to reduce memory allocation
+    mask: Mat,
+    brightness: Mat,
+    resized: Mat,
+    contours: VectorOfVectorOfPoint,
+}
 
 impl ArrowFinder {
     pub fn new() -> ArrowFinder {
-        ArrowFinder {}
+        ArrowFinder {
+            model: None,
+            model_path: String::new(),
+            pruner: Pruner::new(),
+            projector: GroundProjector::new(),
+            mask: Mat::default(),
+            brightness: Mat::default(),
+            resized: Mat::default(),
+            contours: VectorOfVectorOfPoint::new(),
+        }
     }
+
+    fn load_model(path: &str) -> TractResult<ArrowModel> {
+        tract_onnx::onnx()
+            .model_for_path(path)?
+            .with_input_fact(0, f32::fact([1, MODEL_INPUT_LEN]).into())?
+            .into_optimized()?
+            .into_runnable()
+    }
+
+    fn update_model(&mut self, config: &ArrowConfig) {
+        if self.model_path == config.model_path {
+            return;
+        }
+        puffin::profile_function!();
+
+        self.model_path = config.model_path.clone();
+        self.model = match Self::load_model(&config.model_path) {
+            Ok(model) => Some(model),
+            Err(e) => {
+                println!("Could not load arrow model '{}': {}", config.model_path, e);
+                None
+            }
+        };
+    }
+}
+
+// Returns the most likely class and its probability for a crop of the brightness image
+fn classify(
+    model: &ArrowModel, brightness: &Mat, resized: &mut Mat, rect: Rect,
+) -> Result<(ArrowClass, f32), opencv::Error> {
+    puffin::profile_function!();
+
+    let crop = brightness.apply_1(rect)?;
+    resize(
+        &crop,
+        resized,
+        Size::new(MODEL_INPUT_SIDE, MODEL_INPUT_SIDE),
+        0.0,
+        0.0,
+        InterpolationFlags::INTER_AREA.into(),
+    )?;
+    let input: Vec<f32> = resized.data_bytes()?.iter().map(|b| *b as f32).collect();
+
+    let probabilities = run_model(model, input).unwrap_or_else(|e| {
+        println!("Arrow model failed: {}", e);
+        vec![]
+    });
+    let best = probabilities
+        .iter()
+        .enumerate()
+        .reduce(|best, new| if new.1 > best.1 { new } else { best });
+
+    Ok(best
+        .and_then(|(index, probability)| ArrowClass::from_index(index).map(|class| (class, *probability)))
+        .unwrap_or((ArrowClass::None, 0.0)))
+}
+
+fn run_model(model: &ArrowModel, input: Vec<f32>) -> TractResult<Vec<f32>> {
+    let tensor: Tensor = tract_ndarray::Array2::from_shape_vec((1, MODEL_INPUT_LEN), input)?.into();
+    let result = model.run(tvec!(tensor.into()))?;
+    Ok(result[0].to_array_view::<f32>()?.iter().cloned().collect())
 }
+
 impl ObjectFinder for ArrowFinder {
     fn get_points(
-        &mut self, _image: &opencv::core::Mat, _state: &CarState, _: &mut ConfigReader<Config>,
-        _: &dyn PointMap, _: &mut Recorder
+        &mut self, image: &opencv::core::Mat, state: &CarState, config: &ConfigReader<Config>,
+        point_map: &dyn PointMap, _: &Recorder
     ) -> Result<Vec<Point>, opencv::Error> {
-        Ok(vec![])
+        puffin::profile_function!();
+
+        let arrow_cfg = &config.get_last_value().arrow_cfg;
+        self.update_model(arrow_cfg);
+        let model = match &self.model {
+            Some(model) => model,
+            None => return Ok(vec![]),
+        };
+        let colour = arrow_cfg.colour.to_opencv_range();
+        let min_area = arrow_cfg.min_area;
+        let confidence_threshold = arrow_cfg.confidence_threshold;
+
+        {
+            puffin::profile_scope!("thresholding");
+            in_range(image, &colour.low, &colour.high, &mut self.mask)?;
+            // value channel of hsv, used as the grayscale image for the model
+            extract_channel(image, &mut self.brightness, 2)?;
+        }
+        {
+            puffin::profile_scope!("contours");
+            find_contours(
+                &self.mask,
+                &mut self.contours,
+                RetrievalModes::RETR_EXTERNAL.into(),
+                ContourApproximationModes::CHAIN_APPROX_SIMPLE.into(),
+                opencv::core::Point { x: 0, y: 0 },
+            )?;
+        }
+
+        let mut candidates = Vec::new();
+        for contour in self.contours.iter() {
+            if imgproc::contour_area_def(&contour).unwrap_or_default() < min_area {
+                continue;
+            }
+            candidates.push(imgproc::bounding_rect(&contour)?);
+        }
+
+        let mut points = Vec::new();
+        for rect in candidates {
+            let (class, probability) = classify(model, &self.brightness, &mut self.resized, rect)?;
+            let point_type = match class.point_type() {
+                Some(point_type) if probability >= confidence_threshold => point_type,
+                _ => continue,
+            };
+
+            // the bottom of the arrow is where it touches the ground
+            let bottom_center = opencv::core::Point {
+                x: rect.x + rect.width / 2,
+                y: rect.y + rect.height,
+            };
+            let ground = self.projector.image_to_ground(&vec![bottom_center], config);
+            if let Some(pos) = ground.first().copied() {
+                points.push(convert_point_relative_to_global(
+                    Point {
+                        pos,
+                        expire_at: self.pruner.get_point_expiry(pos, probability as f64, point_map),
+                        point_type,
+                        id: rand::random(),
+                        is_virtual: false,
+                        confidence: probability as f64,
+                    },
+                    state,
+                ));
+            }
+        }
+
+        Ok(points)
     }
 }
@@ -0,0 +1,166 @@
+use std::f64::consts::PI;
+
+use opencv::{
+    core::{extract_channel, in_range, Mat, MatTraitConst, Size, Vec4i, Vector},
+    imgproc::{canny, dilate_def, get_structuring_element_def, hough_lines_p, MorphShapes},
+};
+
+use crate::{
+    camera::Recorder, config::file::{Config, ConfigReader, EdgeConfig, LineColour, LineDetector}, points::{Point, PointMap, PointType, Pos}, pruner::Pruner, state::CarState, vision::perspective::{convert_point_relative_to_global, range_confidence, GroundProjector}
+};
+
+use super::ObjectFinder;
+
+// Number of pixels checked along each segment for the line colour
+const COLOUR_CHECKS: i32 = 10;
+
+// Finds straight edges of a line using canny and hough, keeping only segments that run next to the line colour
+pub struct EdgeFinder {
+    line_type: PointType,
+    colour: LineColour,
+    pruner: Pruner,
+    projector: GroundProjector,
+    // stored between frames to reduce memory allocation
+    brightness: Mat,
+    edges: Mat,
+    mask: Mat,
+    near_colour: Mat,
+    segments: Vector<Vec4i>,
+}
+
+impl EdgeFinder {
+    pub fn new(line_type: PointType, colour: LineColour) -> EdgeFinder {
+        EdgeFinder {
+            line_type,
+            colour,
+            pruner: Pruner::new(),
+            projector: GroundProjector::new(),
+            brightness: Mat::default(),
+            edges: Mat::default(),
+            mask: Mat::default(),
+            near_colour: Mat::default(),
+            segments: Vector::new(),
+        }
+    }
+
+    // Fraction of the segment that has the line colour close by
+    fn colour_fraction(&self, segment: &Vec4i) -> f32 {
+        let [x1, y1, x2, y2] = segment.0;
+        let near_count = (0..=COLOUR_CHECKS)
+            .filter(|i| {
+                let x = x1 + (x2 - x1) * i / COLOUR_CHECKS;
+                let y = y1 + (y2 - y1) * i / COLOUR_CHECKS;
+                self.near_colour.at_2d::<u8>(y, x).is_ok_and(|v| *v > 0)
+            })
+            .count();
+        near_count as f32 / (COLOUR_CHECKS + 1) as f32
+    }
+
+    // Evenly spaced ground positions along the segments, with how much of the segment was next to the line colour
+    // Nothing for a spacing of 0 or less, which would be endless points
+    fn ground_points_from_segments(&mut self, config: &ConfigReader<Config>, spacing: f64) -> Vec<(Pos, f64)> {
+        puffin::profile_function!();
+
+        if spacing <= 0.0 {
+            return Vec::new();
+        }
+
+        let mut result = Vec::new();
+        for segment in self.segments.iter() {
+            let [x1, y1, x2, y2] = segment.0;
+            let ends = vec![opencv::core::Point { x: x1, y: y1 }, opencv::core::Point { x: x2, y: y2 }];
+            let ground = self.projector.image_to_ground(&ends, config);
+            let colour_fraction = self.colour_fraction(&segment) as f64;
+            // one of the ends was excluded
+            if ground.len() != 2 {
+                continue;
+            }
+            let length = ground[0].dist(ground[1]);
+            let count = (length / spacing).floor() as usize;
+            for i in 0..=count {
+                let t = if count == 0 { 0.5 } else { i as f64 / count as f64 };
+                result.push((ground[0].lerp(ground[1], t), colour_fraction));
+            }
+        }
+        result
+    }
+}
+
+impl ObjectFinder for EdgeFinder {
+    fn get_points(
+        &mut self, image: &opencv::core::Mat, state: &CarState, config: &ConfigReader<Config>,
+        point_map: &dyn PointMap, _: &Recorder
+    ) -> Result<Vec<Point>, opencv::Error> {
+        puffin::profile_function!();
+
+        let config_val = config.get_last_value();
+        if config_val.detector_for_line(&self.colour) != LineDetector::Edge {
+            return Ok(vec![]);
+        }
+        let colour = config_val.colour_for_line(&self.colour);
+        let EdgeConfig {
+            canny_low,
+            canny_high,
+            hough_threshold,
+            min_segment_length,
+            max_segment_gap,
+            colour_search_radius,
+            min_colour_fraction,
+            sample_spacing,
+            ..
+        } = config_val.edge_cfg;
+
+        {
+            puffin::profile_scope!("edges");
+            extract_channel(image, &mut self.brightness, 2)?;
+            canny(&self.brightness, &mut self.edges, canny_low, canny_high, 3, false)?;
+        }
+        {
+            puffin::profile_scope!("thresholding");
+            in_range(image, &colour.low, &colour.high, &mut self.mask)?;
+            let kernel_size = colour_search_radius * 2 + 1;
+            let kernel = get_structuring_element_def(
+                MorphShapes::MORPH_ELLIPSE.into(),
+                Size::new(kernel_size, kernel_size),
+            )?;
+            dilate_def(&self.mask, &mut self.near_colour, &kernel)?;
+        }
+        {
+            puffin::profile_scope!("hough");
+            hough_lines_p(
+                &self.edges,
+                &mut self.segments,
+                1.0,
+                PI / 180.0,
+                hough_threshold,
+                min_segment_length,
+                max_segment_gap,
+            )?;
+        }
+
+        let kept = Vector::<Vec4i>::from_iter(
+            self.segments.iter().filter(|segment| self.colour_fraction(segment) >= min_colour_fraction),
+        );
+        self.segments = kept;
+
+        let ground = self.ground_points_from_segments(config, sample_spacing);
+
+        Ok(ground
+            .into_iter()
+            .map(|(pos, colour_fraction)| {
+                let confidence = colour_fraction * range_confidence(pos);
+                convert_point_relative_to_global(
+                    Point {
+                        pos,
+                        expire_at: self.pruner.get_point_expiry(pos, confidence, point_map),
+                        point_type: self.line_type,
+                        id: rand::random(),
+                        is_virtual: false,
+                        confidence,
+                    },
+                    state,
+                )
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,69 @@
+use crate::{
+    config::file::LaneConfig,
+    points::{Point, PointType, Pos},
+    state::CarState,
+};
+
+// Virtual points are only a guess, so are trusted less than the points they were made from
+const VIRTUAL_CONFIDENCE: f64 = 0.5;
+// Other points on the same line closer than this are used to find which way the line goes
+const NEIGHBOUR_RADIUS: f64 = 0.2;
+
+// When only one of the lane lines was seen this frame, adds virtual points where the other one should be
+// so the planner doesn't wander over to where it is
+pub fn add_missing_line(points: &mut Vec<Point>, state: &CarState, config: &LaneConfig) {
+    puffin::profile_function!();
+
+    if !config.single_line_fallback {
+        return;
+    }
+    let has_left = points.iter().any(|p| p.point_type == PointType::LeftLine);
+    let has_right = points.iter().any(|p| p.point_type == PointType::RightLine);
+    let (seen, missing, side) = match (has_left, has_right) {
+        (true, false) => (PointType::LeftLine, PointType::RightLine, 1.0),
+        (false, true) => (PointType::RightLine, PointType::LeftLine, -1.0),
+        _ => return,
+    };
+
+    let line: Vec<&Point> = points.iter().filter(|p| p.point_type == seen).collect();
+    let heading = Pos { x: 1.0, y: 0.0 }.rotate(state.angle);
+    let virtual_points: Vec<Point> = line
+        .iter()
+        .filter_map(|point| {
+            let direction = line_direction(point.pos, &line, heading)?;
+            // y is to the right of x, so a quarter turn goes from along the line to the right of it
+            let across = direction.rotate(std::f64::consts::FRAC_PI_2 * side);
+            Some(Point {
+                pos: point.pos + across * config.lane_width,
+                expire_at: point.expire_at,
+                point_type: missing,
+                id: rand::random(),
+                is_virtual: true,
+                confidence: point.confidence * VIRTUAL_CONFIDENCE,
+            })
+        })
+        .collect();
+    points.extend(virtual_points);
+}
+
+// Unit direction of the line at a point, pointing the same way as the car
+// None if there aren't any other points close enough to tell
+fn line_direction(at: Pos, line: &Vec<&Point>, heading: Pos) -> Option<Pos> {
+    let mut total = Pos { x: 0.0, y: 0.0 };
+    for other in line {
+        let offset = other.pos - at;
+        let dist = offset.dist(Pos { x: 0.0, y: 0.0 });
+        if dist == 0.0 || dist > NEIGHBOUR_RADIUS {
+            continue;
+        }
+        // points behind and in front both count towards the same direction
+        let aligned = if offset.x * heading.x + offset.y * heading.y >= 0.0 { offset } else { offset * -1.0 };
+        total = total + aligned * (1.0 / dist);
+    }
+
+    let length = total.dist(Pos { x: 0.0, y: 0.0 });
+    if length == 0.0 {
+        return None;
+    }
+    Some(total * (1.0 / length))
+}
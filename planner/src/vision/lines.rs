@@ -1,13 +1,13 @@
+use std::f64::consts::FRAC_PI_2;
+
 use opencv::{
     core::{in_range, Mat, VecN},
     highgui,
     imgproc::{self, circle, cvt_color, find_contours, ColorConversionCodes, ContourApproximationModes, RetrievalModes},
     types::VectorOfVectorOfPoint,
 };
-use rand::Rng;
-
 use crate::{
-    camera::Recorder, config::file::{Config, ConfigReader, LineColour}, points::{Point, PointMap, PointType, Pos}, pruner::Pruner, state::CarState, vision::perspective::{convert_point_relative_to_global, perspective_correct}
+    camera::Recorder, config::file::{Config, ConfigReader, LineColour, LineDetector}, points::{Point, PointMap, PointType, Pos}, pruner::Pruner, state::CarState, vision::perspective::{convert_point_relative_to_global, range_confidence, GroundProjector}
 };
 
 use super::ObjectFinder;
@@ -17,6 +17,7 @@ pub struct LineFinder {
     line_type: PointType,
     colour: LineColour,
     pruner: Pruner,
+    projector: GroundProjector,
     // stored between frames to reduce memory allocation
     contours: VectorOfVectorOfPoint,
     mask: Mat,
@@ -24,6 +25,14 @@ pub struct LineFinder {
 }
 
 
+// Area to outline length of a contour that is definitely tape, about half the width of the tape in pixels
+const CONFIDENT_AREA_RATIO: f64 = 3.0;
+// Pixels either side of a point on an outline used to find which way it faces
+const NORMAL_SPAN: usize = 5;
+// How much the outward direction on the ground has to point towards the lane for an edge to count as the inside
+// of the tape, 0 would also keep the ends of the tape, 1 only edges that run exactly along it
+const LANE_FACING_MIN: f64 = 0.3;
+
 impl LineFinder {
     pub fn new(obstacle_type: PointType, colour: LineColour, name: String) -> LineFinder {
         LineFinder {
@@ -32,12 +41,17 @@ impl LineFinder {
             line_type: obstacle_type,
             colour: colour,
             pruner: Pruner::new(),
+            projector: GroundProjector::new(),
             name: name,
         }
     }
-    fn is_valid_contour(border_points: &opencv::core::Vector<opencv::core::Point>, min_border: i32, min_area_ratio: f32) -> bool {
+    // None if the contour isn't a line, otherwise how solid it is from 0 to 1
+    // Thin or ragged outlines have less area for their length and are more likely to be noise in the mask
+    fn contour_confidence(
+        border_points: &opencv::core::Vector<opencv::core::Point>, min_border: i32, min_area_ratio: f32,
+    ) -> Option<f64> {
         if border_points.len() < min_border as usize {
-            return false;
+            return None;
         }
 
         let area = imgproc::contour_area_def(border_points).unwrap_or_default();
@@ -45,44 +59,157 @@ impl LineFinder {
         let area_ratio = area / (border_points_num as f64);
         // println!("{area_ratio}, {area}/{border_points_num}");
         if area_ratio < min_area_ratio as f64{
-            return false;
+            return None;
         }
 
-        true
+        Some((area_ratio / CONFIDENT_AREA_RATIO).min(1.0))
     }
 
-    fn points_from_contours(&self, config: &mut ConfigReader<Config>) -> Vec<opencv::core::Point> {
+    // Evenly spaced ground positions along the outline of each contour, or just the lane side of it for the lane lines
+    // Spacing is measured on the ground so far away lines get the same number of points per meter as close ones
+    fn points_from_contours(&mut self, config: &ConfigReader<Config>) -> Result<Vec<(Pos, f64)>, opencv::Error> {
         puffin::profile_function!();
 
-        let config = config.get_value();
+        let contour_cfg = &config.get_last_value().contour_cfg;
 
-        self.contours
-            .iter()
-            .flat_map(|contour| {
-                if LineFinder::is_valid_contour(&contour, config.contour_cfg.min_boundry, config.contour_cfg.min_area_ratio) {
-                    // offset by random amount avoid always sampling the same points along the outline
-                    let skip = rand::thread_rng().gen_range(0..SAMPLE_EVERY);
-                    contour.iter().skip(skip).step_by(SAMPLE_EVERY).collect() // TODO: try and get this to lazy evaluate
-                } else {
-                    vec![]
+        let mut result = Vec::new();
+        for contour in self.contours.iter() {
+            let confidence =
+                match LineFinder::contour_confidence(&contour, contour_cfg.min_boundry, contour_cfg.min_area_ratio) {
+                    Some(confidence) => confidence,
+                    None => continue,
+                };
+            let paths = match lane_side(self.line_type) {
+                Some(side) => {
+                    let outline = contour.to_vec();
+                    let ground = self.projector.image_path_to_ground_unfiltered(&outline, config);
+                    lane_facing_runs(&outline, &ground, side)
                 }
-            })
-            .collect()
+                None => {
+                    // outlines are closed, so go back to the start
+                    let mut path = contour.to_vec();
+                    path.push(contour.get(0)?);
+                    vec![path]
+                }
+            };
+            for path in paths {
+                for run in self.projector.image_path_to_ground(&path, config) {
+                    result.extend(resample(&run, contour_cfg.sample_spacing).into_iter().map(|pos| (pos, confidence)));
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+// Which way the lane is from a line going away from the car, 1 for right and -1 for left
+// None if the line doesn't have a side, e.g. the finish line
+fn lane_side(line_type: PointType) -> Option<f64> {
+    match line_type {
+        PointType::LeftLine => Some(1.0),
+        PointType::RightLine => Some(-1.0),
+        _ => None,
     }
 }
 
-const SAMPLE_EVERY: usize = 20;
+// Splits an outline into the connected runs of points on the edge that faces the lane, ground has where each point is
+// Only the inside edge of the tape is the boundary of the track, and the outside edge would double the points
+// Sides are worked out on the ground going along the tape away from the car, so tape running across the image in a
+// corner keeps its inside edge the same as tape running up the image
+pub fn lane_facing_runs(outline: &[opencv::core::Point], ground: &[Pos], side: f64) -> Vec<Vec<opencv::core::Point>> {
+    let len = outline.len();
+    if len == 0 || ground.len() != len {
+        return vec![];
+    }
+
+    // the ends of the tape are roughly the two points furthest apart, found starting from the point nearest the car
+    let car = Pos { x: 0.0, y: 0.0 };
+    let furthest_from = |from: Pos| *ground.iter().max_by(|a, b| a.dist(from).total_cmp(&b.dist(from))).unwrap();
+    let nearest = *ground.iter().min_by(|a, b| a.dist(car).total_cmp(&b.dist(car))).unwrap();
+    let end = furthest_from(nearest);
+    let other_end = furthest_from(end);
+    let (start, end) = if end.dist(car) < other_end.dist(car) { (end, other_end) } else { (other_end, end) };
+    let length = start.dist(end);
+    if length == 0.0 {
+        return vec![];
+    }
+    // y is to the right of x, so a quarter turn goes from along the tape to the right of it
+    let towards_lane = ((end - start) * (1.0 / length)).rotate(FRAC_PI_2 * side);
+
+    // the direction of the outline decides which side of it is outside the tape
+    let twice_area: f64 = (0..len)
+        .map(|i| {
+            let (a, b) = (ground[i], ground[(i + 1) % len]);
+            a.x * b.y - b.x * a.y
+        })
+        .sum();
+    let facing: Vec<bool> = (0..len)
+        .map(|i| {
+            // tangent over a few pixels to smooth out the steps in the outline
+            let tangent = ground[(i + NORMAL_SPAN) % len] - ground[(i + len - NORMAL_SPAN % len) % len];
+            let outward = tangent.rotate(if twice_area > 0.0 { -FRAC_PI_2 } else { FRAC_PI_2 });
+            let length = tangent.dist(car);
+            length > 0.0 && (outward.x * towards_lane.x + outward.y * towards_lane.y) / length > LANE_FACING_MIN
+        })
+        .collect();
+
+    // start from a point that isn't kept so no run wraps around the start of the outline
+    let start = match facing.iter().position(|keep| !keep) {
+        Some(start) => start,
+        None => return vec![outline.to_vec()],
+    };
+    let mut runs = Vec::new();
+    let mut run = Vec::new();
+    for offset in 0..len {
+        let i = (start + offset) % len;
+        if facing[i] {
+            run.push(outline[i]);
+        } else if !run.is_empty() {
+            runs.push(std::mem::take(&mut run));
+        }
+    }
+    if !run.is_empty() {
+        runs.push(run);
+    }
+    runs
+}
+
+// Points every spacing meters along a path, starting at its first point
+// Nothing for a spacing of 0 or less, which would never get anywhere along the path
+fn resample(path: &[Pos], spacing: f64) -> Vec<Pos> {
+    if path.is_empty() || spacing <= 0.0 {
+        return vec![];
+    }
+
+    let mut result = vec![path[0]];
+    // distance along the path since the last point was added
+    let mut since_last = 0.0;
+    for pair in path.windows(2) {
+        let length = pair[0].dist(pair[1]);
+        let mut along = spacing - since_last;
+        while along <= length {
+            result.push(pair[0].dist_along(pair[1], along));
+            along += spacing;
+        }
+        since_last = length - (along - spacing);
+    }
+    result
+}
 
 impl ObjectFinder for LineFinder {
     fn get_points(
-        &mut self, image: &opencv::core::Mat, state: &CarState, config: &mut ConfigReader<Config>,
-        point_map: &dyn PointMap, recorder: &mut Recorder
+        &mut self, image: &opencv::core::Mat, state: &CarState, config: &ConfigReader<Config>,
+        point_map: &dyn PointMap, recorder: &Recorder
     ) -> Result<Vec<Point>, opencv::Error> {
         puffin::profile_function!();
 
+        if config.get_last_value().detector_for_line(&self.colour) != LineDetector::Contour {
+            return Ok(vec![]);
+        }
+
         {
             puffin::profile_scope!("thresholding");
-            let config_val = config.get_value();
+            let config_val = config.get_last_value();
             in_range(
                 image,
                 &config_val.colour_for_line(&self.colour).low,
@@ -105,24 +232,33 @@ impl ObjectFinder for LineFinder {
             )?;
         }
 
-        let image_points = self.points_from_contours(config);
-        let points = perspective_correct(&image_points, config);
-        draw_mask_debug(&self.line_type.to_string(), &self.mask, &image_points)?;
+        let points = self.points_from_contours(config)?;
+        if DRAW_MASK {
+            let image_points: Vec<opencv::core::Point> = self
+                .projector
+                .ground_to_image(&points.iter().map(|(pos, _)| *pos).collect::<Vec<Pos>>(), config)
+                .iter()
+                .map(|p| opencv::core::Point {
+                    x: p.x as i32,
+                    y: p.y as i32,
+                })
+                .collect();
+            draw_mask_debug(&self.line_type.to_string(), &self.mask, &image_points)?;
+        }
 
         Ok(points
             .iter()
-            .map(|p| {
-                let pos = Pos {
-                    x: p.x as f64,
-                    y: p.y as f64,
-                };
-                let confidence = self.pruner.get_point_expiry(pos, point_map);
+            .map(|(pos, contour_confidence)| {
+                let pos = *pos;
+                let confidence = contour_confidence * range_confidence(pos);
                 convert_point_relative_to_global(
                     Point {
                         pos,
-                        expire_at: confidence,
+                        expire_at: self.pruner.get_point_expiry(pos, confidence, point_map),
                         point_type: self.line_type,
                         id: rand::random(),
+                        is_virtual: false,
+                        confidence,
                     },
                     state,
                 )
@@ -20,8 +20,8 @@ fn jitter() -> Pos {
 
 impl ObjectFinder for FakePointProvider {
     fn get_points(
-        &mut self, _: &opencv::core::Mat, _: &CarState, _: &mut ConfigReader<Config>,
-        point_map: &dyn PointMap, _: &mut Recorder
+        &mut self, _: &opencv::core::Mat, _: &CarState, _: &ConfigReader<Config>,
+        point_map: &dyn PointMap, _: &Recorder
     ) -> Result<Vec<Point>, opencv::Error> {
         let mut pruner = Pruner::new();
         let all_lines = vec![
@@ -50,15 +50,19 @@ impl ObjectFinder for FakePointProvider {
         let mut points = vec![
             Point {
                 id: rand::random(),
-                expire_at: pruner.get_point_expiry(Pos { x: -2.75, y: 0.0 }, point_map),
+                expire_at: pruner.get_point_expiry(Pos { x: -2.75, y: 0.0 }, 1.0, point_map),
                 pos: Pos { x: -2.75, y: 0.0 },
                 point_type: PointType::ArrowLeft,
+                is_virtual: false,
+                confidence: 1.0,
             },
             Point {
                 id: rand::random(),
-                expire_at: pruner.get_point_expiry(Pos { x: -2.75, y: -2.5 }, point_map),
+                expire_at: pruner.get_point_expiry(Pos { x: -2.75, y: -2.5 }, 1.0, point_map),
                 pos: Pos { x: -2.75, y: -2.5 },
                 point_type: PointType::ArrowRight,
+                is_virtual: false,
+                confidence: 1.0,
             },
         ];
         for lines_of_type in all_lines {
@@ -68,9 +72,11 @@ impl ObjectFinder for FakePointProvider {
                     let pos = line[0].dist_along(line[1], rand::random::<f64>() * line_dist) + jitter();
                     points.push(Point {
                         id: rand::random(),
-                        expire_at: pruner.get_point_expiry(pos, point_map),
+                        expire_at: pruner.get_point_expiry(pos, 1.0, point_map),
                         point_type: lines_of_type.point_type,
                         pos,
+                        is_virtual: false,
+                        confidence: 1.0,
                     });
                 }
             }
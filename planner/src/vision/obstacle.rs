@@ -1,19 +1,115 @@
+use std::collections::BTreeMap;
+
 use crate::{
-    camera::Recorder, config::file::{Config, ConfigReader}, points::{Point, PointMap}, state::CarState, vision::ObjectFinder
+    camera::Recorder, config::file::{Config, ConfigReader}, points::{Point, PointMap, PointType, Pos}, pruner::Pruner, state::CarState, vision::{perspective::{convert_point_relative_to_global, range_confidence, GroundProjector}, ObjectFinder}
+};
+use opencv::{
+    core::{in_range, Mat},
+    imgproc::{self, find_contours, ContourApproximationModes, RetrievalModes},
+    types::VectorOfVectorOfPoint,
 };
-use opencv::core::Mat;
 
 // Finds points along just the bottom edge of something
-struct ObstacleFinder {
-    // obstacle_type: PointType,
-    // colour: ColourRange,
+pub struct ObstacleFinder {
+    obstacle_type: PointType,
+    pruner: Pruner,
+    projector: GroundProjector,
+    // stored between frames to reduce memory allocation
+    contours: VectorOfVectorOfPoint,
+    mask: Mat,
+}
+
+const SAMPLE_EVERY: usize = 10;
+// How many times the min area a contour has to be to be fully trusted
+const CONFIDENT_AREA_SCALE: f64 = 3.0;
+
+impl ObstacleFinder {
+    pub fn new(obstacle_type: PointType) -> ObstacleFinder {
+        ObstacleFinder {
+            obstacle_type,
+            pruner: Pruner::new(),
+            projector: GroundProjector::new(),
+            contours: VectorOfVectorOfPoint::new(),
+            mask: Mat::default(),
+        }
+    }
+
+    // The lowest point in each column of the contour, which is where the obstacle meets the ground
+    fn bottom_edge(contour: &opencv::core::Vector<opencv::core::Point>) -> Vec<opencv::core::Point> {
+        let mut lowest = BTreeMap::<i32, i32>::new();
+        for point in contour.iter() {
+            let y = lowest.entry(point.x).or_insert(point.y);
+            *y = (*y).max(point.y);
+        }
+        lowest
+            .into_iter()
+            .step_by(SAMPLE_EVERY)
+            .map(|(x, y)| opencv::core::Point { x, y })
+            .collect()
+    }
+
+    // Ground positions along the bottom of each big enough contour, with how sure it is that it's an obstacle
+    fn points_from_contours(&mut self, min_area: f64, config: &ConfigReader<Config>) -> Vec<(Pos, f64)> {
+        puffin::profile_function!();
+
+        let mut result = Vec::new();
+        for contour in self.contours.iter() {
+            let area = imgproc::contour_area_def(&contour).unwrap_or_default();
+            if area < min_area {
+                continue;
+            }
+            // small blobs are more likely to be noise in the mask
+            let size_confidence = (area / (min_area * CONFIDENT_AREA_SCALE)).min(1.0);
+            let ground = self.projector.image_to_ground(&Self::bottom_edge(&contour), config);
+            result.extend(ground.into_iter().map(|pos| (pos, size_confidence)));
+        }
+        result
+    }
 }
-impl ObstacleFinder {}
 
 impl ObjectFinder for ObstacleFinder {
     fn get_points(
-        &mut self, _image: &Mat, _state: &CarState, _: &mut ConfigReader<Config>, _: &dyn PointMap, _: &mut Recorder
+        &mut self, image: &Mat, state: &CarState, config: &ConfigReader<Config>, point_map: &dyn PointMap,
+        _: &Recorder
     ) -> Result<Vec<Point>, opencv::Error> {
-        Ok(vec![])
+        puffin::profile_function!();
+
+        let min_area = {
+            puffin::profile_scope!("thresholding");
+            let obstacle_cfg = &config.get_last_value().obstacle_cfg;
+            let colour = obstacle_cfg.colour.to_opencv_range();
+            in_range(image, &colour.low, &colour.high, &mut self.mask)?;
+            obstacle_cfg.min_area
+        };
+        {
+            puffin::profile_scope!("contours");
+            find_contours(
+                &self.mask,
+                &mut self.contours,
+                RetrievalModes::RETR_EXTERNAL.into(),
+                ContourApproximationModes::CHAIN_APPROX_NONE.into(),
+                opencv::core::Point { x: 0, y: 0 },
+            )?;
+        }
+
+        let points = self.points_from_contours(min_area, config);
+
+        Ok(points
+            .into_iter()
+            .map(|(pos, size_confidence)| {
+                let confidence = size_confidence * range_confidence(pos);
+                convert_point_relative_to_global(
+                    Point {
+                        pos,
+                        expire_at: self.pruner.get_point_expiry(pos, confidence, point_map),
+                        point_type: self.obstacle_type,
+                        id: rand::random(),
+                        is_virtual: false,
+                        confidence,
+                    },
+                    state,
+                )
+            })
+            .collect())
     }
 }
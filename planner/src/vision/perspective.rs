@@ -1,13 +1,11 @@
 use opencv::{
-    core::{perspective_transform, Mat, MatExprTraitConst, Point2f, CV_32FC2, DECOMP_LU},
-    imgproc::get_perspective_transform,
+    calib3d::{project_points_def, undistort_points},
+    core::{perspective_transform, Mat, MatExprTraitConst, Point2f, Point3f, Vector, CV_32FC2, DECOMP_LU},
+    imgproc::{get_perspective_transform, point_polygon_test},
 };
 
 use crate::{
-    config::{
-        file::{Config, ConfigReader},
-        image::{EXCLUDE_RECT, TOP_CROP},
-    },
+    config::file::{Config, ConfigReader},
     points::{Point, Pos},
     state::CarState,
 };
@@ -20,7 +18,7 @@ pub fn get_perspective_points_config(config_obj: &Config) -> PerspectiveTransfor
     for line in &config_obj.perspective.image {
         perspective_points_image.push(Point2f {
             x: line[0],
-            y: line[1] - TOP_CROP as f32,
+            y: line[1] - config_obj.image_cfg.top_crop as f32,
         });
     }
     let mut perspective_points_ground = opencv::core::Vector::<Point2f>::new();
@@ -33,41 +31,232 @@ pub fn get_perspective_points_config(config_obj: &Config) -> PerspectiveTransfor
     (perspective_points_image, perspective_points_ground)
 }
 
-pub fn perspective_correct(
-    points_ints_in_vec: &Vec<opencv::core::Point2i>, config: &mut ConfigReader<Config>,
-) -> Vec<Pos> {
-    puffin::profile_function!();
+// Converts between positions in the cropped image and positions on the ground relative to the car
+// Image points are undistorted using the lens config before the homography is applied
+// The transforms are only regenerated when the config file changes
+pub struct GroundProjector {
+    config_version: Option<u32>,
+    to_ground: Mat,
+    to_image: Mat,
+    camera_matrix: Mat,
+    distortion: Mat,
+    // fx, fy, cx, cy
+    intrinsics: [f64; 4],
+    top_crop: i32,
+    exclude: Vec<Vector<Point2f>>,
+}
+
+impl GroundProjector {
+    pub fn new() -> GroundProjector {
+        GroundProjector {
+            config_version: None,
+            to_ground: Mat::default(),
+            to_image: Mat::default(),
+            camera_matrix: Mat::default(),
+            distortion: Mat::default(),
+            intrinsics: [1., 1., 0., 0.],
+            top_crop: 0,
+            exclude: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, config: &ConfigReader<Config>) {
+        if self.config_version == Some(config.version()) {
+            return;
+        }
+        puffin::profile_function!();
 
-    let mut point_floats_in_vec = Vec::<opencv::core::Point2f>::new();
-    for point in points_ints_in_vec {
-        if !EXCLUDE_RECT.contains(*point) {
-            point_floats_in_vec.push(opencv::core::Point2f {
+        let config_obj = config.get_last_value();
+        self.top_crop = config_obj.image_cfg.top_crop;
+        self.exclude = config_obj
+            .image_cfg
+            .exclude
+            .iter()
+            .map(|polygon| Vector::from_iter(polygon.iter().map(|p| Point2f { x: p[0] as f32, y: p[1] as f32 })))
+            .collect();
+
+        let lens = &config_obj.lens_cfg;
+        self.camera_matrix = Mat::from_slice_2d(&lens.camera_matrix).unwrap();
+        self.distortion = Mat::from_slice_2d(&[lens.distortion.as_slice()]).unwrap();
+        self.intrinsics = [
+            lens.camera_matrix[0][0],
+            lens.camera_matrix[1][1],
+            lens.camera_matrix[0][2],
+            lens.camera_matrix[1][2],
+        ];
+
+        // the calibration points were measured on the distorted image
+        let (distorted_image, perspective_points_ground) = get_perspective_points_config(config_obj);
+        let perspective_points_image = Vector::from_iter(self.undistort(&distorted_image.to_vec()));
+        self.to_ground =
+            get_perspective_transform(&perspective_points_image, &perspective_points_ground, DECOMP_LU).unwrap();
+        self.to_image =
+            get_perspective_transform(&perspective_points_ground, &perspective_points_image, DECOMP_LU).unwrap();
+        self.config_version = Some(config.version());
+    }
+
+    // Projects points in the cropped image onto the ground, points inside the exclude polygons are dropped
+    pub fn image_to_ground(
+        &mut self, points_ints_in_vec: &Vec<opencv::core::Point2i>, config: &ConfigReader<Config>,
+    ) -> Vec<Pos> {
+        puffin::profile_function!();
+        self.update(config);
+
+        let mut point_floats_in_vec = Vec::<opencv::core::Point2f>::new();
+        for point in points_ints_in_vec {
+            let point_float = opencv::core::Point2f {
                 x: point.x as f32,
                 y: point.y as f32,
-            });
+            };
+            if !self.is_excluded(point_float) {
+                point_floats_in_vec.push(point_float);
+            }
         }
+        self.project(&point_floats_in_vec)
+    }
+
+    // Projects a path of connected points in the cropped image onto the ground
+    // The path is split into separate runs where it goes through an exclude polygon, so the runs stay connected
+    pub fn image_path_to_ground(
+        &mut self, path: &[opencv::core::Point2i], config: &ConfigReader<Config>,
+    ) -> Vec<Vec<Pos>> {
+        puffin::profile_function!();
+        self.update(config);
+
+        let mut runs = Vec::new();
+        let mut run = Vec::<Point2f>::new();
+        for point in path {
+            let point_float = Point2f {
+                x: point.x as f32,
+                y: point.y as f32,
+            };
+            if self.is_excluded(point_float) {
+                if !run.is_empty() {
+                    runs.push(self.project(&run));
+                    run.clear();
+                }
+            } else {
+                run.push(point_float);
+            }
+        }
+        if !run.is_empty() {
+            runs.push(self.project(&run));
+        }
+        runs
+    }
+
+    // Projects every point of a path in the cropped image onto the ground, including ones in the exclude polygons
+    pub fn image_path_to_ground_unfiltered(
+        &mut self, path: &[opencv::core::Point2i], config: &ConfigReader<Config>,
+    ) -> Vec<Pos> {
+        puffin::profile_function!();
+        self.update(config);
+
+        self.project(&path.iter().map(|point| Point2f { x: point.x as f32, y: point.y as f32 }).collect())
+    }
+
+    fn project(&self, points: &Vec<Point2f>) -> Vec<Pos> {
+        transform_points(&self.undistort(points), &self.to_ground)
+            .iter()
+            .map(|p| Pos {
+                x: p.y as f64,
+                y: p.x as f64,
+            })
+            .collect()
     }
 
-    let points_in_mat = Mat::from_slice(&point_floats_in_vec).unwrap();
+    // Projects ground positions relative to the car into the cropped image
+    pub fn ground_to_image(&mut self, positions: &[Pos], config: &ConfigReader<Config>) -> Vec<Point2f> {
+        puffin::profile_function!();
+        self.update(config);
 
-    let mut result_mat = opencv::core::Mat::zeros(point_floats_in_vec.len() as i32, 2, CV_32FC2)
+        let ground_points: Vec<Point2f> = positions
+            .iter()
+            .map(|p| Point2f {
+                x: p.y as f32,
+                y: p.x as f32,
+            })
+            .collect();
+        self.distort(&transform_points(&ground_points, &self.to_image))
+    }
+
+    fn is_excluded(&self, cropped_point: Point2f) -> bool {
+        let full_frame = Point2f {
+            x: cropped_point.x,
+            y: cropped_point.y + self.top_crop as f32,
+        };
+        self.exclude
+            .iter()
+            .any(|polygon| point_polygon_test(polygon, full_frame, false).unwrap_or(-1.) >= 0.)
+    }
+
+    // Removes lens distortion from points in the cropped image, the intrinsics are for the full frame
+    fn undistort(&self, points: &Vec<Point2f>) -> Vec<Point2f> {
+        puffin::profile_function!();
+        if points.is_empty() {
+            return vec![];
+        }
+
+        let top_crop = self.top_crop as f32;
+        let full_frame = Vector::from_iter(points.iter().map(|p| Point2f { x: p.x, y: p.y + top_crop }));
+        let mut undistorted = Vector::<Point2f>::new();
+        // passing the camera matrix as the new projection keeps the output in pixels
+        undistort_points(
+            &full_frame,
+            &mut undistorted,
+            &self.camera_matrix,
+            &self.distortion,
+            &Mat::default(),
+            &self.camera_matrix,
+        )
+        .unwrap();
+        undistorted.iter().map(|p| Point2f { x: p.x, y: p.y - top_crop }).collect()
+    }
+
+    // Inverse of undistort
+    fn distort(&self, points: &Vec<Point2f>) -> Vec<Point2f> {
+        if points.is_empty() {
+            return vec![];
+        }
+
+        let [fx, fy, cx, cy] = self.intrinsics;
+        let normalised = Vector::from_iter(points.iter().map(|p| Point3f {
+            x: ((p.x as f64 - cx) / fx) as f32,
+            y: ((p.y as f64 + self.top_crop as f64 - cy) / fy) as f32,
+            z: 1.,
+        }));
+        let zero = Vector::<f64>::from_iter([0., 0., 0.]);
+        let mut distorted = Vector::<Point2f>::new();
+        project_points_def(&normalised, &zero, &zero, &self.camera_matrix, &self.distortion, &mut distorted).unwrap();
+        distorted.iter().map(|p| Point2f { x: p.x, y: p.y - self.top_crop as f32 }).collect()
+    }
+}
+
+fn transform_points(points: &Vec<Point2f>, transform: &Mat) -> Vec<Point2f> {
+    if points.is_empty() {
+        return vec![];
+    }
+    let points_in_mat = Mat::from_slice(points).unwrap();
+
+    let mut result_mat = opencv::core::Mat::zeros(points.len() as i32, 2, CV_32FC2)
         .unwrap()
         .to_mat()
         .unwrap();
-    // TODO: don't regenerate every frame
-    let config_obj = config.get_value();
-    let (perspective_points_image, perspective_points_ground) = get_perspective_points_config(config_obj);
-    let transform = get_perspective_transform(&perspective_points_image, &perspective_points_ground, DECOMP_LU).unwrap();
-    perspective_transform(&points_in_mat, &mut result_mat, &transform).unwrap();
-
-    let mut result_final = vec![];
-    for p in result_mat.iter::<Point2f>().unwrap() {
-        result_final.push(Pos {
-            x: p.1.y as f64,
-            y: p.1.x as f64,
-        });
-    }
-    result_final
+    perspective_transform(&points_in_mat, &mut result_mat, transform).unwrap();
+
+    result_mat.iter::<Point2f>().unwrap().map(|p| p.1).collect()
+}
+
+// Further away each pixel covers more of the ground, so points found there are less accurate
+const FULL_CONFIDENCE_RANGE: f64 = 0.6;
+const MIN_CONFIDENCE_RANGE: f64 = 3.0;
+const MIN_RANGE_CONFIDENCE: f64 = 0.2;
+
+// Confidence from 0 to 1 for a point relative to the car based only on how far away it is
+pub fn range_confidence(pos: Pos) -> f64 {
+    let range = pos.dist(Pos { x: 0., y: 0. });
+    let t = ((range - FULL_CONFIDENCE_RANGE) / (MIN_CONFIDENCE_RANGE - FULL_CONFIDENCE_RANGE)).clamp(0., 1.);
+    1. - t * (1. - MIN_RANGE_CONFIDENCE)
 }
 
 pub fn convert_point_relative_to_global(point: Point, car: &CarState) -> Point {
@@ -0,0 +1,262 @@
+// Measures how the car moved by lining up the lane line points seen on each frame with the ones from the frame before
+// Matching is done with point to line ICP in the car's frame, starting from what the command model says happened
+// Lines only hold the car across them, so along straight or evenly curving lines the guess is kept for how far it went
+use crate::{
+    config::file::{Config, ConfigReader},
+    messages::path::SimpleDrive,
+    odom::{PoseCovariance, RelativeStateProvider},
+    points::{Point, PointType, Pos},
+    state::CarState,
+};
+
+// Fewer matched points than this can't be trusted to give the movement
+const MIN_MATCHES: usize = 15;
+const MAX_ITERATIONS: usize = 20;
+// Points further than this from any point on the last frame aren't matched, in meters
+const MAX_MATCH_DIST: f64 = 0.15;
+// Stop iterating once an iteration moves the estimate less than this
+const CONVERGED_DIST: f64 = 0.001;
+const CONVERGED_ANGLE: f64 = 0.001;
+// Matches that end up further from their line than this on average mean the frames didn't really line up
+const MAX_MEAN_RESIDUAL: f64 = 0.02;
+// More different than this from the command model is more likely a bad match than the car slipping
+const MAX_CORRECTION_DIST: f64 = 0.3;
+const MAX_CORRECTION_ANGLE: f64 = 0.3;
+// Points this close are used to find which way the line goes at a point
+const NORMAL_RADIUS: f64 = 0.15;
+// Keeps each step small in directions the lines don't say much about, as a fraction of the most certain direction
+const DAMPING: f64 = 0.01;
+
+pub struct VisualRelativeStateProvider {
+    // used as the starting guess and when matching fails
+    fallback: Box<dyn RelativeStateProvider>,
+    // line points seen on the last frame relative to the car, where the car ended up on it and when it was captured
+    last_points: Option<(Vec<Pos>, CarState, f64)>,
+    // speed measured by vision on the last frame
+    speed: Option<f64>,
+}
+
+impl RelativeStateProvider for VisualRelativeStateProvider {
+    fn set_command(&mut self, command: SimpleDrive, config: &mut ConfigReader<Config>) {
+        self.fallback.set_command(command, config);
+    }
+
+    fn get_movement(&mut self) -> CarState {
+        puffin::profile_function!();
+
+        let guess = self.fallback.get_movement();
+        CarState {
+            speed: self.speed.unwrap_or(guess.speed),
+            ..guess
+        }
+    }
+
+    fn pose_covariance(&self) -> Option<PoseCovariance> {
+        self.fallback.pose_covariance()
+    }
+
+    // Moves the car and the points to where the lines line up with the ones on the last frame
+    fn add_frame_points(&mut self, points: &mut [Point], state: &mut CarState, timestamp: f64) {
+        puffin::profile_function!();
+
+        let to_car = |pos: Pos, car: &CarState| -> Pos { (pos - car.pos).rotate(-car.angle) };
+        let current: Vec<Pos> = points
+            .iter()
+            .filter(|p| !p.is_virtual && (p.point_type == PointType::LeftLine || p.point_type == PointType::RightLine))
+            .map(|p| to_car(p.pos, state))
+            .collect();
+
+        let last = self.last_points.replace((current, *state, timestamp));
+        let (previous, previous_state, seen_at) = match last {
+            Some(last) => last,
+            None => return,
+        };
+        let current = &self.last_points.as_ref().unwrap().0;
+
+        // what was applied since the last frame, the starting guess for matching
+        let applied = CarState {
+            pos: to_car(state.pos, &previous_state),
+            angle: state.angle - previous_state.angle,
+            ..*state
+        };
+        let motion = match estimate_motion(&previous, current, applied) {
+            Some(motion) => motion,
+            None => {
+                self.speed = None;
+                return;
+            }
+        };
+
+        let old_state = *state;
+        *state = previous_state + motion;
+        for point in points.iter_mut() {
+            point.pos = to_car(point.pos, &old_state).rotate(state.angle) + state.pos;
+        }
+        self.last_points.as_mut().unwrap().1 = *state;
+        // from when the frames were captured, so time spent paused on a recording doesn't count
+        let elapsed = timestamp - seen_at;
+        self.speed = (elapsed > 0.0).then_some(motion.pos.x / elapsed);
+    }
+
+    fn add_pose_correction(&mut self, correction: &CarState) {
+        // the next frame is matched from where the car ended up
+        if let Some((_, state, _)) = &mut self.last_points {
+            *state = *state + *correction;
+        }
+        self.fallback.add_pose_correction(correction);
+    }
+
+    fn reset(&mut self) {
+        self.last_points = None;
+        self.speed = None;
+        self.fallback.reset();
+    }
+
+    fn measures_speed(&self) -> bool {
+        true
+    }
+}
+
+impl VisualRelativeStateProvider {
+    pub fn new(fallback: Box<dyn RelativeStateProvider>) -> VisualRelativeStateProvider {
+        VisualRelativeStateProvider {
+            fallback,
+            last_points: None,
+            speed: None,
+        }
+    }
+}
+
+// Finds how the car moved between two frames of points relative to the car, starting from a guess
+// The result is the pose of the car on the current frame relative to the car on the previous one
+// None if the points didn't line up well enough to say
+pub fn estimate_motion(previous: &[Pos], current: &[Pos], guess: CarState) -> Option<CarState> {
+    let motion = align_points(previous, current, guess)?;
+    if motion.pos.dist(guess.pos) > MAX_CORRECTION_DIST || (motion.angle - guess.angle).abs() > MAX_CORRECTION_ANGLE {
+        return None;
+    }
+    Some(motion)
+}
+
+// Finds the pose that best moves the points onto the lines through the target points, starting from a guess
+// None if too few points matched or they didn't end up close to the lines
+pub fn align_points(target: &[Pos], points: &[Pos], guess: CarState) -> Option<CarState> {
+    puffin::profile_function!();
+
+    if target.len() < MIN_MATCHES || points.len() < MIN_MATCHES {
+        return None;
+    }
+    // worked out the first time each target point is matched
+    let mut normals: Vec<Option<Option<Pos>>> = vec![None; target.len()];
+
+    let mut angle = guess.angle;
+    let mut offset = guess.pos;
+    let mut residual = 0.0;
+    for _ in 0..MAX_ITERATIONS {
+        // gauss newton on the distance of each point from the line through its match
+        let mut hessian = [[0.0; 3]; 3];
+        let mut gradient = [0.0; 3];
+        let mut matches = 0;
+        residual = 0.0;
+        for &point in points {
+            let rotated = point.rotate(angle);
+            let moved = rotated + offset;
+            let (index, matched) = match nearest(target, moved) {
+                Some(nearest) if nearest.1.dist(moved) < MAX_MATCH_DIST => nearest,
+                _ => continue,
+            };
+            let normal = match *normals[index].get_or_insert_with(|| line_normal(target, matched)) {
+                Some(normal) => normal,
+                None => continue,
+            };
+
+            let error = dot(normal, moved - matched);
+            let jacobian = [normal.x, normal.y, dot(normal, rotated.rotate(std::f64::consts::FRAC_PI_2))];
+            for ((row, gradient), j_i) in hessian.iter_mut().zip(&mut gradient).zip(jacobian) {
+                *gradient += j_i * error;
+                for (value, j_j) in row.iter_mut().zip(jacobian) {
+                    *value += j_i * j_j;
+                }
+            }
+            residual += error.abs();
+            matches += 1;
+        }
+        if matches < MIN_MATCHES {
+            return None;
+        }
+        residual /= matches as f64;
+
+        let damping = DAMPING * (0..3).map(|i| hessian[i][i]).fold(0.0, f64::max);
+        for (i, row) in hessian.iter_mut().enumerate() {
+            row[i] += damping;
+        }
+        let step = solve(hessian, gradient.map(|g| -g))?;
+        offset = offset + Pos { x: step[0], y: step[1] };
+        angle += step[2];
+        if step[0].hypot(step[1]) < CONVERGED_DIST && step[2].abs() < CONVERGED_ANGLE {
+            break;
+        }
+    }
+
+    if residual > MAX_MEAN_RESIDUAL {
+        return None;
+    }
+    Some(CarState {
+        pos: offset,
+        angle,
+        ..guess
+    })
+}
+
+fn nearest(points: &[Pos], to: Pos) -> Option<(usize, Pos)> {
+    points
+        .iter()
+        .copied()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.dist(to).total_cmp(&b.dist(to)))
+}
+
+fn dot(a: Pos, b: Pos) -> f64 {
+    a.x * b.x + a.y * b.y
+}
+
+// Unit normal of the line through the points around a point, None if there aren't enough points to tell
+fn line_normal(points: &[Pos], at: Pos) -> Option<Pos> {
+    let near: Vec<Pos> = points.iter().copied().filter(|p| p.dist(at) < NORMAL_RADIUS).collect();
+    if near.len() < 3 {
+        return None;
+    }
+    let center = near.iter().fold(Pos { x: 0.0, y: 0.0 }, |sum, p| sum + *p) * (1.0 / near.len() as f64);
+    let (mut xx, mut xy, mut yy) = (0.0, 0.0, 0.0);
+    for point in &near {
+        let d = *point - center;
+        xx += d.x * d.x;
+        xy += d.x * d.y;
+        yy += d.y * d.y;
+    }
+    // the line goes along the direction the points are most spread out in
+    let along = 0.5 * (2.0 * xy).atan2(xx - yy);
+    Some(Pos { x: -along.sin(), y: along.cos() })
+}
+
+// Solves a x = b with cramer's rule, None if a is singular
+fn solve(a: [[f64; 3]; 3], b: [f64; 3]) -> Option<[f64; 3]> {
+    let det = determinant(&a);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let mut x = [0.0; 3];
+    for (i, value) in x.iter_mut().enumerate() {
+        let mut replaced = a;
+        for (row, value) in replaced.iter_mut().zip(b) {
+            row[i] = value;
+        }
+        *value = determinant(&replaced) / det;
+    }
+    Some(x)
+}
+
+fn determinant(m: &[[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
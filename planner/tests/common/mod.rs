@@ -0,0 +1,46 @@
+// Fixtures shared by the integration tests, each test only uses some of them
+#![allow(dead_code)]
+
+use std::{f64::consts::FRAC_PI_2, path::Path};
+
+use planner::{
+    config::file::{Config, ConfigReader},
+    points::Pos,
+    state::CarState,
+};
+
+// Distance between points along the simulated lines, in meters
+pub const SPACING: f64 = 0.05;
+
+// The config used by the vision fixtures, so tuning the real config doesn't break the tests
+pub fn config() -> ConfigReader<Config> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/vision/config.json");
+    ConfigReader::new(path.to_str().unwrap(), |s| serde_json::from_str::<Config>(s).unwrap())
+}
+
+pub fn pose(x: f64, y: f64, angle: f64) -> CarState {
+    CarState {
+        pos: Pos { x, y },
+        angle,
+        ..Default::default()
+    }
+}
+
+// Straight lines 0.8m apart that bend to the left 1m ahead, sampled starting part way between samples
+pub fn bend(phase: f64) -> Vec<Pos> {
+    let mut points = Vec::new();
+    for (y, radius) in [(0.4, 0.6), (-0.4, 1.4)] {
+        let mut x = -1.0 + phase * SPACING;
+        while x < 1.0 {
+            points.push(Pos { x, y });
+            x += SPACING;
+        }
+        let mut along = x - 1.0;
+        while along < radius * FRAC_PI_2 {
+            let angle = along / radius;
+            points.push(Pos { x: 1.0 + radius * angle.sin(), y: 1.0 - radius * angle.cos() });
+            along += SPACING;
+        }
+    }
+    points
+}
@@ -0,0 +1,126 @@
+// Runs the filter against simulated sensor traces where the true path of the car is known
+
+mod common;
+
+use std::time::Duration;
+
+use common::config;
+use planner::{
+    ekf::{EkfRelativeStateProvider, Measurement},
+    messages::path::SimpleDrive,
+    odom::RelativeStateProvider,
+    points::Pos,
+    state::CarState,
+};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+const STEP: Duration = Duration::from_millis(20);
+const STEPS: usize = 250;
+const TRUE_SPEED: f64 = 1.0;
+const TRUE_CURVATURE: f64 = 0.5;
+// the fixture config has a speed fudge of 6, so the command model thinks the car goes 20% faster than it does
+const COMMAND: SimpleDrive = SimpleDrive { speed: 0.2, curvature: 0.5 };
+const SENSOR_NOISE: f64 = 0.1;
+
+// Roughly normal noise with the given standard deviation
+fn noise(rng: &mut SmallRng, std_dev: f64) -> f64 {
+    ((0..12).map(|_| rng.gen::<f64>()).sum::<f64>() - 6.0) * std_dev
+}
+
+// Drives the car around a circle, returning how far the filter ended up from the truth
+fn run_trace(with_sensors: bool) -> f64 {
+    let mut rng = SmallRng::seed_from_u64(1);
+    let mut config = config();
+    let mut ekf = EkfRelativeStateProvider::new(Vec::new());
+    ekf.set_command(COMMAND, &mut config);
+
+    let mut truth = CarState {
+        speed: TRUE_SPEED,
+        curvature: TRUE_CURVATURE,
+        ..Default::default()
+    };
+    let mut estimate = CarState::default();
+    for _ in 0..STEPS {
+        truth = truth.step_time(STEP);
+        if with_sensors {
+            ekf.add_measurement(Measurement::WheelSpeed {
+                speed: TRUE_SPEED + noise(&mut rng, SENSOR_NOISE),
+                variance: SENSOR_NOISE * SENSOR_NOISE,
+            });
+            ekf.add_measurement(Measurement::YawRate {
+                rate: TRUE_SPEED * TRUE_CURVATURE + noise(&mut rng, SENSOR_NOISE),
+                variance: SENSOR_NOISE * SENSOR_NOISE,
+            });
+        }
+        estimate += ekf.movement_over(STEP);
+    }
+    estimate.pos.dist(truth.pos)
+}
+
+#[test]
+fn test_sensors_correct_command_model() {
+    let command_only = run_trace(false);
+    let fused = run_trace(true);
+    assert!(command_only > 0.5, "command model should drift, was off by {command_only}");
+    assert!(fused < 0.1, "fused estimate was off by {fused}");
+}
+
+#[test]
+fn test_covariance_grows_without_measurements() {
+    let mut config = config();
+    let mut ekf = EkfRelativeStateProvider::new(Vec::new());
+    ekf.set_command(COMMAND, &mut config);
+
+    let mut last_variance = 0.0;
+    for _ in 0..10 {
+        ekf.movement_over(Duration::from_millis(100));
+        let covariance = ekf.pose_covariance().unwrap();
+        assert!(covariance[0][0] > last_variance);
+        last_variance = covariance[0][0];
+    }
+}
+
+#[test]
+fn test_pose_measurement_corrects_position() {
+    let mut ekf = EkfRelativeStateProvider::new(Vec::new());
+    for _ in 0..10 {
+        ekf.movement_over(Duration::from_millis(100));
+    }
+    let before = ekf.pose_covariance().unwrap();
+
+    ekf.add_measurement(Measurement::Pose {
+        pos: Pos { x: 0.5, y: -0.2 },
+        angle: 0.1,
+        variance: [1e-5, 1e-5, 1e-5],
+    });
+    let movement = ekf.movement_over(Duration::ZERO);
+    assert!((movement.pos.x - 0.5).abs() < 0.05);
+    assert!((movement.pos.y + 0.2).abs() < 0.05);
+    assert!((movement.angle - 0.1).abs() < 0.05);
+
+    let after = ekf.pose_covariance().unwrap();
+    for i in 0..3 {
+        assert!(after[i][i] < before[i][i]);
+    }
+}
+
+#[test]
+fn test_pose_correction_is_kept() {
+    // just started, so the filter is sure of where it is and only moves a little of the way towards the correction
+    let mut ekf = EkfRelativeStateProvider::new(Vec::new());
+    ekf.movement_over(Duration::from_millis(10));
+    let before = ekf.pose_covariance().unwrap();
+
+    // the car has already been moved by all of this, so the filter shouldn't move it again or take it back
+    let correction = CarState {
+        pos: Pos { x: 0.05, y: 0.0 },
+        angle: 0.02,
+        ..Default::default()
+    };
+    let mut car = correction;
+    ekf.add_pose_correction(&correction);
+    car += ekf.movement_over(Duration::ZERO);
+    assert!(car.pos.dist(correction.pos) < 0.001, "{:?}", car);
+    assert!((car.angle - correction.angle).abs() < 0.001, "{:?}", car);
+    assert!(ekf.pose_covariance().unwrap()[0][0] < before[0][0]);
+}
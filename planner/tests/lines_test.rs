@@ -0,0 +1,62 @@
+// Picks the lane side of tape outlines, with a simple projection standing in for the camera
+
+use opencv::core::Point;
+use planner::{points::Pos, vision::lines::lane_facing_runs};
+
+// Every pixel around a polygon, like an outline from find_contours
+fn outline(corners: &[(i32, i32)]) -> Vec<Point> {
+    let mut outline = Vec::new();
+    for (i, &(x0, y0)) in corners.iter().enumerate() {
+        let (x1, y1) = corners[(i + 1) % corners.len()];
+        let steps = (x1 - x0).abs().max((y1 - y0).abs());
+        for step in 0..steps {
+            let t = step as f64 / steps as f64;
+            outline.push(Point {
+                x: (x0 as f64 + (x1 - x0) as f64 * t).round() as i32,
+                y: (y0 as f64 + (y1 - y0) as f64 * t).round() as i32,
+            });
+        }
+    }
+    outline
+}
+
+// The car is at the middle of the bottom of the image, 5mm per pixel
+fn to_ground(outline: &[Point]) -> Vec<Pos> {
+    outline
+        .iter()
+        .map(|p| Pos {
+            x: (200 - p.y) as f64 * 0.005,
+            y: (p.x - 160) as f64 * 0.005,
+        })
+        .collect()
+}
+
+fn kept(outline: &[Point], side: f64) -> Vec<Point> {
+    lane_facing_runs(outline, &to_ground(outline), side).into_iter().flatten().collect()
+}
+
+#[test]
+fn test_tape_beside_car_keeps_edge_towards_lane() {
+    let tape = outline(&[(60, 0), (70, 0), (70, 190), (60, 190)]);
+
+    let left = kept(&tape, 1.0);
+    assert!(left.len() > 150);
+    assert!(left.iter().all(|p| p.x > 65));
+    let right = kept(&tape, -1.0);
+    assert!(right.len() > 150);
+    assert!(right.iter().all(|p| p.x < 65));
+}
+
+#[test]
+fn test_tape_across_image_keeps_edge_towards_lane() {
+    // rising slightly to the right ahead of the car, like the left line going into a right turn
+    let tape = outline(&[(40, 140), (280, 110), (280, 120), (40, 150)]);
+    let middle = |x: i32| 145.0 - (x - 40) as f64 * 30.0 / 240.0;
+
+    let left = kept(&tape, 1.0);
+    assert!(left.len() > 200, "only kept {}", left.len());
+    assert!(left.iter().all(|p| p.y as f64 > middle(p.x)));
+    let right = kept(&tape, -1.0);
+    assert!(right.len() > 200, "only kept {}", right.len());
+    assert!(right.iter().all(|p| (p.y as f64) < middle(p.x)));
+}
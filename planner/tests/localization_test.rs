@@ -0,0 +1,115 @@
+// Lines up a simulated frame with a map of the same lines when the car's state has drifted
+
+mod common;
+
+use common::{bend, pose};
+use planner::{
+    config::file::LocalizationConfig,
+    localization::correct_state,
+    points::{GridPointMap, Point, PointMap, PointType, Pos},
+    state::CarState,
+};
+
+fn config(max_correction: f64) -> LocalizationConfig {
+    LocalizationConfig {
+        enabled: true,
+        max_correction_dist: max_correction,
+        max_correction_angle: max_correction,
+        min_map_points: 30,
+    }
+}
+
+fn to_points(world: &[Pos]) -> Vec<Point> {
+    world
+        .iter()
+        .map(|&pos| Point {
+            pos,
+            expire_at: f64::MAX,
+            point_type: PointType::LeftLine,
+            id: rand::random(),
+            is_virtual: false,
+            confidence: 1.0,
+        })
+        .collect()
+}
+
+fn map() -> GridPointMap {
+    let mut map = GridPointMap::new();
+    map.add_points(&to_points(&bend(0.0)));
+    map.add_points(&to_points(&bend(0.5)));
+    map
+}
+
+// What the car at truth sees, placed in the map by a state that has drifted from it
+fn frame(truth: CarState, state: CarState) -> Vec<Point> {
+    let seen: Vec<Pos> = bend(0.3)
+        .into_iter()
+        .map(|p| (p - truth.pos).rotate(-truth.angle))
+        .filter(|p| p.x > 0.3 && p.x < 2.0 && p.y.abs() < 1.5)
+        .map(|p| p.rotate(state.angle) + state.pos)
+        .collect();
+    to_points(&seen)
+}
+
+#[test]
+fn test_drift_is_corrected() {
+    let truth = pose(0.2, 0.0, 0.0);
+    let mut state = truth + pose(0.03, -0.02, 0.02);
+    let mut points = frame(truth, state);
+
+    let correction = correct_state(&mut points, &mut state, &map(), &config(0.1), None);
+    assert!(correction.is_some());
+    assert!(state.pos.dist(truth.pos) < 0.01, "{:?}", state);
+    assert!((state.angle - truth.angle).abs() < 0.005, "{:?}", state);
+
+    // the points are moved with the car so they land on the map's lines
+    let expected = frame(truth, truth);
+    for (point, expected) in points.iter().zip(&expected) {
+        assert!(point.pos.dist(expected.pos) < 0.02);
+    }
+}
+
+#[test]
+fn test_too_big_a_correction_is_rejected() {
+    let truth = pose(0.2, 0.0, 0.0);
+    let drifted = truth + pose(0.03, -0.02, 0.02);
+    let mut state = drifted;
+    let mut points = frame(truth, state);
+    let before: Vec<Pos> = points.iter().map(|p| p.pos).collect();
+
+    assert!(correct_state(&mut points, &mut state, &map(), &config(0.01), None).is_none());
+    assert_eq!(state, drifted);
+    assert!(points.iter().zip(&before).all(|(point, before)| point.pos == *before));
+}
+
+#[test]
+fn test_odometry_covariance_only_tightens_the_limit() {
+    let truth = pose(0.2, 0.0, 0.0);
+    let drifted = truth + pose(0.03, -0.02, 0.02);
+    let unsure = [[0.01, 0.0, 0.0], [0.0, 0.01, 0.0], [0.0, 0.0, 0.01]];
+    let sure = [[0.00001, 0.0, 0.0], [0.0, 0.00001, 0.0], [0.0, 0.0, 0.00001]];
+
+    let mut state = drifted;
+    let mut points = frame(truth, state);
+    assert!(correct_state(&mut points, &mut state, &map(), &config(0.01), Some(unsure)).is_none());
+    assert!(correct_state(&mut points, &mut state, &map(), &config(0.1), Some(sure)).is_none());
+    assert_eq!(state, drifted);
+
+    assert!(correct_state(&mut points, &mut state, &map(), &config(0.1), Some(unsure)).is_some());
+    assert!(state.pos.dist(truth.pos) < 0.01, "{:?}", state);
+}
+
+#[test]
+fn test_nothing_to_match_against() {
+    let truth = pose(0.2, 0.0, 0.0);
+    let mut state = truth;
+    let mut points = frame(truth, state);
+
+    assert!(correct_state(&mut points, &mut state, &GridPointMap::new(), &config(0.1), None).is_none());
+    let disabled = LocalizationConfig {
+        enabled: false,
+        ..config(0.1)
+    };
+    assert!(correct_state(&mut points, &mut state, &map(), &disabled, None).is_none());
+    assert_eq!(state, truth);
+}
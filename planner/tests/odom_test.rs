@@ -0,0 +1,66 @@
+mod common;
+
+use std::time::Duration;
+
+use common::config;
+use planner::{
+    encoder::SimulatedPulseCounter,
+    messages::path::SimpleDrive,
+    odom::{EncoderRelativeStateProvider, RelativeStateProvider},
+};
+
+const METERS_PER_PULSE: f64 = 0.005;
+
+fn provider() -> (EncoderRelativeStateProvider, SimulatedPulseCounter) {
+    let counter = SimulatedPulseCounter::new();
+    let provider = EncoderRelativeStateProvider::new(Box::new(counter.clone()), METERS_PER_PULSE);
+    (provider, counter)
+}
+
+#[test]
+fn test_encoder_distance() {
+    let (mut provider, counter) = provider();
+    counter.add_pulses(100);
+
+    let movement = provider.movement_over(Duration::from_millis(100));
+    assert!((movement.pos.x - 0.5).abs() < 1e-9);
+    assert!(movement.pos.y.abs() < 1e-9);
+    assert!(movement.speed > 0.0);
+
+    // pulses are only counted once
+    let movement = provider.movement_over(Duration::from_millis(100));
+    assert_eq!(movement.pos.x, 0.0);
+}
+
+#[test]
+fn test_encoder_speed_settles_on_measured_speed() {
+    let (mut provider, counter) = provider();
+    let mut speed = 0.0;
+    for _ in 0..50 {
+        // 1 m/s
+        counter.add_pulses(20);
+        speed = provider.movement_over(Duration::from_millis(100)).speed;
+    }
+    assert!((speed - 1.0).abs() < 0.01);
+}
+
+#[test]
+fn test_encoder_direction_and_turn_from_command() {
+    let (mut provider, counter) = provider();
+    let mut config = config();
+
+    provider.set_command(SimpleDrive { speed: -0.5, curvature: 0.0 }, &mut config);
+    counter.add_pulses(100);
+    assert!((provider.movement_over(Duration::from_millis(100)).pos.x + 0.5).abs() < 1e-9);
+
+    // coasting after the command stops keeps going the same way
+    provider.set_command(SimpleDrive { speed: 0.0, curvature: 0.0 }, &mut config);
+    counter.add_pulses(10);
+    assert!(provider.movement_over(Duration::from_millis(100)).pos.x < 0.0);
+
+    provider.set_command(SimpleDrive { speed: 0.5, curvature: 1.0 }, &mut config);
+    counter.add_pulses(100);
+    let movement = provider.movement_over(Duration::from_millis(100));
+    assert!((movement.angle - 0.5).abs() < 1e-9);
+    assert!(movement.pos.y > 0.0);
+}
@@ -0,0 +1,149 @@
+// Runs every frame in tests/fixtures/vision through the vision pipeline and compares the ground points found with the
+// expected points stored next to the frame (frame.png -> frame.json)
+// After a change to vision that is meant to change the output, update the expected points with:
+//     UPDATE_GOLDEN=1 cargo test --test vision_golden_test
+// and check the diff of the json files before committing them
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use opencv::imgcodecs::{imread, IMREAD_COLOR};
+use planner::{
+    camera::Recorder,
+    config::file::{Config, ConfigReader},
+    points::{GridPointMap, Pos},
+    state::CarState,
+    vision::Vision,
+};
+use serde::{Deserialize, Serialize};
+
+const FIXTURES_DIR: &str = "tests/fixtures/vision";
+// how far a point can be from where it was expected, in meters
+const POSITION_TOLERANCE: f64 = 0.03;
+// how many points of each type can be gained or lost, as a fraction of the expected count
+const COUNT_TOLERANCE: f64 = 0.1;
+
+#[derive(Serialize, Deserialize)]
+struct GoldenPoint {
+    point_type: String,
+    x: f64,
+    y: f64,
+}
+
+impl GoldenPoint {
+    fn pos(&self) -> Pos {
+        Pos { x: self.x, y: self.y }
+    }
+}
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(FIXTURES_DIR)
+}
+
+fn frames() -> Vec<PathBuf> {
+    let mut frames: Vec<PathBuf> = fs::read_dir(fixtures_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+        .collect();
+    frames.sort();
+    frames
+}
+
+// Points relative to the car, which is at the origin facing along x
+fn run_vision(vision: &mut Vision, config: &mut ConfigReader<Config>, frame: &Path) -> Vec<GoldenPoint> {
+    let image = imread(frame.to_str().unwrap(), IMREAD_COLOR).unwrap();
+    let point_map = GridPointMap::new();
+    let points = vision.get_points_from_image(&image, CarState::default(), config, &point_map, &Recorder::default());
+
+    let mut result: Vec<GoldenPoint> = points
+        .iter()
+        .map(|point| GoldenPoint {
+            point_type: point.point_type.to_string(),
+            x: point.pos.x,
+            y: point.pos.y,
+        })
+        .collect();
+    // the order points are found in doesn't matter, sorting keeps the diffs of the golden files small
+    result.sort_by(|a, b| {
+        (&a.point_type, a.x, a.y)
+            .partial_cmp(&(&b.point_type, b.x, b.y))
+            .unwrap()
+    });
+    result
+}
+
+// Points in from that have no point of the same type in to within the tolerance
+fn unmatched<'a>(from: &'a [GoldenPoint], to: &[GoldenPoint]) -> Vec<&'a GoldenPoint> {
+    from.iter()
+        .filter(|a| {
+            !to.iter()
+                .any(|b| a.point_type == b.point_type && a.pos().dist(b.pos()) <= POSITION_TOLERANCE)
+        })
+        .collect()
+}
+
+fn compare(expected: &[GoldenPoint], actual: &[GoldenPoint]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let mut types: Vec<&String> = expected.iter().chain(actual.iter()).map(|p| &p.point_type).collect();
+    types.sort();
+    types.dedup();
+    for point_type in types {
+        let expected_count = expected.iter().filter(|p| &p.point_type == point_type).count();
+        let actual_count = actual.iter().filter(|p| &p.point_type == point_type).count();
+        let allowed = (expected_count as f64 * COUNT_TOLERANCE).ceil() as usize;
+        if expected_count.abs_diff(actual_count) > allowed {
+            problems.push(format!("expected {expected_count} {point_type} points, found {actual_count}"));
+        }
+    }
+
+    for point in unmatched(expected, actual) {
+        problems.push(format!("missing {} point at ({:.3}, {:.3})", point.point_type, point.x, point.y));
+    }
+    for point in unmatched(actual, expected) {
+        problems.push(format!("unexpected {} point at ({:.3}, {:.3})", point.point_type, point.x, point.y));
+    }
+    problems
+}
+
+#[test]
+fn test_vision_matches_golden() {
+    let update = env::var("UPDATE_GOLDEN").is_ok_and(|value| value == "1");
+    let config_path = fixtures_dir().join("config.json");
+    let mut config = ConfigReader::new(config_path.to_str().unwrap(), |s| serde_json::from_str::<Config>(s).unwrap());
+    let mut vision = Vision::new();
+
+    let frames = frames();
+    assert!(!frames.is_empty(), "no frames found in {FIXTURES_DIR}");
+
+    let mut failures = Vec::new();
+    for frame in frames {
+        let actual = run_vision(&mut vision, &mut config, &frame);
+        let golden_path = frame.with_extension("json");
+        let name = frame.file_name().unwrap().to_string_lossy().into_owned();
+
+        if update {
+            println!("writing {} points for {}", actual.len(), name);
+            fs::write(&golden_path, serde_json::to_string_pretty(&actual).unwrap() + "\n").unwrap();
+            continue;
+        }
+        if !golden_path.exists() {
+            failures.push(format!("{name}: no expected points, {} not found", golden_path.display()));
+            continue;
+        }
+
+        let expected: Vec<GoldenPoint> = serde_json::from_str(&fs::read_to_string(&golden_path).unwrap()).unwrap();
+        for problem in compare(&expected, &actual) {
+            failures.push(format!("{name}: {problem}"));
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "vision output changed, if this is expected run UPDATE_GOLDEN=1 cargo test --test vision_golden_test\n{}",
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,157 @@
+// Lines up simulated frames of lane line points where the true movement of the car is known
+
+mod common;
+
+use common::{bend, pose, SPACING};
+use planner::{
+    config::file::{Config, ConfigReader},
+    messages::path::SimpleDrive,
+    odom::RelativeStateProvider,
+    points::{Point, PointType, Pos},
+    state::CarState,
+    visual_odom::{estimate_motion, VisualRelativeStateProvider},
+};
+
+// seconds between frames
+const FRAME_TIME: f64 = 0.05;
+
+fn straight(phase: f64) -> Vec<Pos> {
+    [0.4, -0.4]
+        .iter()
+        .flat_map(|&y| (-20..80).map(move |i| Pos { x: (i as f64 + phase) * SPACING, y }))
+        .collect()
+}
+
+// The points the camera would see from the car
+fn seen_from(world: &[Pos], car: CarState) -> Vec<Pos> {
+    world
+        .iter()
+        .map(|&p| (p - car.pos).rotate(-car.angle))
+        .filter(|p| p.x > 0.3 && p.x < 2.0 && p.y.abs() < 1.5)
+        .collect()
+}
+
+#[test]
+fn test_motion_found_at_bend() {
+    let truth = pose(0.1, 0.005, 0.03);
+    let previous = seen_from(&bend(0.0), pose(0.0, 0.0, 0.0));
+    let current = seen_from(&bend(0.5), truth);
+
+    let motion = estimate_motion(&previous, &current, pose(0.08, 0.0, 0.0)).unwrap();
+    assert!(motion.pos.dist(truth.pos) < 0.01, "{:?}", motion);
+    assert!((motion.angle - truth.angle).abs() < 0.005, "{:?}", motion);
+}
+
+#[test]
+fn test_straight_lines_keep_guess_along_them() {
+    let previous = seen_from(&straight(0.0), pose(0.0, 0.0, 0.0));
+    let current = seen_from(&straight(0.5), pose(0.1, 0.0, 0.0));
+
+    let motion = estimate_motion(&previous, &current, pose(0.08, 0.02, 0.01)).unwrap();
+    assert!((motion.pos.x - 0.08).abs() < 0.005, "{:?}", motion);
+    assert!(motion.pos.y.abs() < 0.005, "{:?}", motion);
+    assert!(motion.angle.abs() < 0.005, "{:?}", motion);
+}
+
+#[test]
+fn test_too_few_points_fails() {
+    let previous = seen_from(&bend(0.0), pose(0.0, 0.0, 0.0));
+    let current: Vec<Pos> = previous.iter().take(5).copied().collect();
+    assert!(estimate_motion(&previous, &current, pose(0.0, 0.0, 0.0)).is_none());
+}
+
+// Always says the car moved the same amount
+struct FixedMovement(CarState);
+
+impl RelativeStateProvider for FixedMovement {
+    fn set_command(&mut self, _command: SimpleDrive, _config: &mut ConfigReader<Config>) {}
+
+    fn get_movement(&mut self) -> CarState {
+        self.0
+    }
+}
+
+fn to_points(seen: &[Pos], state: CarState) -> Vec<Point> {
+    seen.iter()
+        .map(|&p| Point {
+            pos: p.rotate(state.angle) + state.pos,
+            expire_at: 0.0,
+            point_type: PointType::LeftLine,
+            id: 0,
+            is_virtual: false,
+            confidence: 1.0,
+        })
+        .collect()
+}
+
+// Drives through the bend with a command model that is short on distance and thinks the car is turning
+// Something else puts the car back where it really is on the frames given, like lining up with the map does
+fn drive(with_vision: bool, put_back_on: &[usize]) -> (f64, f64) {
+    let movement = pose(0.05, 0.0, 0.0);
+    let fallback = FixedMovement(pose(0.04, 0.0, 0.01));
+    let mut provider: Box<dyn RelativeStateProvider> = if with_vision {
+        Box::new(VisualRelativeStateProvider::new(Box::new(fallback)))
+    } else {
+        Box::new(fallback)
+    };
+
+    let mut truth = pose(0.0, 0.0, 0.0);
+    let mut state = pose(0.0, 0.0, 0.0);
+    for frame in 0..10 {
+        truth += movement;
+        state += provider.get_movement();
+        let seen = seen_from(&bend((frame as f64 * 0.37) % 1.0), truth);
+        let mut points = to_points(&seen, state);
+        provider.add_frame_points(&mut points, &mut state, frame as f64 * FRAME_TIME);
+
+        // the points are moved along with the car
+        for (point, expected) in points.iter().zip(to_points(&seen, state)) {
+            assert!(point.pos.dist(expected.pos) < 1e-9);
+        }
+        if put_back_on.contains(&frame) {
+            let correction = CarState {
+                pos: (truth.pos - state.pos).rotate(-state.angle),
+                angle: truth.angle - state.angle,
+                ..state
+            };
+            state = state + correction;
+            provider.add_pose_correction(&correction);
+        }
+    }
+    (state.pos.dist(truth.pos), (state.angle - truth.angle).abs())
+}
+
+#[test]
+fn test_vision_corrects_command_model() {
+    let (blind_dist, blind_angle) = drive(false, &[]);
+    let (dist, angle) = drive(true, &[]);
+    assert!(blind_dist > 0.08 && blind_angle > 0.05);
+    // nothing to match the first frame against, so it stays off by that much
+    assert!(dist < 0.03, "off by {dist}");
+    assert!(angle < 0.01, "off by {angle}");
+}
+
+#[test]
+fn test_corrections_from_elsewhere_are_kept() {
+    let (dist, angle) = drive(true, &[5]);
+    assert!(dist < 0.01, "off by {dist}");
+    assert!(angle < 0.01, "off by {angle}");
+}
+
+#[test]
+fn test_speed_from_frame_times_is_forgotten_on_reset() {
+    let mut provider = VisualRelativeStateProvider::new(Box::new(FixedMovement(pose(0.04, 0.0, 0.0))));
+    let mut truth = pose(0.0, 0.0, 0.0);
+    for frame in 0..3 {
+        truth += pose(0.05, 0.0, 0.0);
+        let mut state = truth;
+        let mut points = to_points(&seen_from(&bend(frame as f64 * 0.37), truth), state);
+        // speed comes from when the frames were captured, not when they were processed
+        provider.add_frame_points(&mut points, &mut state, frame as f64 * FRAME_TIME);
+    }
+    let speed = provider.get_movement().speed;
+    assert!((speed - 0.05 / FRAME_TIME).abs() < 0.1, "speed was {speed}");
+
+    provider.reset();
+    assert_eq!(provider.get_movement().speed, 0.0);
+}
@@ -1,6 +1,8 @@
 import cv2
 import numpy as np
 import os
+import tensorflow as tf
+import tf2onnx
 from sklearn.model_selection import train_test_split
 from sklearn.preprocessing import LabelEncoder
 from keras.models import Sequential
@@ -25,7 +27,8 @@ def load_data(dataset_dir):
                 contours, _ = cv2.findContours(black_mask, cv2.RETR_EXTERNAL, cv2.CHAIN_APPROX_SIMPLE)
                 for contour in contours:
                     x, y, w, h = cv2.boundingRect(contour)
-                    arrow_roi = image[y:y+h, x:x+w]
+                    # planner feeds the model the hsv value channel, which is max(b, g, r)
+                    arrow_roi = image[y:y+h, x:x+w].max(axis=2)
                     X.append(extract_features(arrow_roi))
                     y.append(label)
     return np.array(X), np.array(y)
@@ -55,4 +58,9 @@ model.fit(X_train, y_train, epochs=10, batch_size=32, validation_split=0.1)
 
 # Evaluate model
 loss, accuracy = model.evaluate(X_test, y_test)
-print(f'Test Loss: {loss}, Test Accuracy: {accuracy}'
+print(f'Test Loss: {loss}, Test Accuracy: {accuracy}')
+
+# Export for the planner's ArrowFinder, which loads arrow_cfg.model_path from config.json
+input_signature = [tf.TensorSpec((1, 2500), tf.float32, name="input")]
+tf2onnx.convert.from_keras(model, input_signature=input_signature, output_path="../planner/arrow_model.onnx")
+print(f'Classes in model output order: {list(label_encoder.classes_)}')